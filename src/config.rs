@@ -1,27 +1,155 @@
+use dyndns2::same_host;
 use errors::*;
+use ipnet::IpNet;
+use password::Password;
+use policy::bogons;
 use template::Template;
+use std::ffi::OsString;
 use std::path::Path;
 
 static IP_RESOLV_METHOD_DYNDNS2: &str = "DynDns2";
 static IP_RESOLV_METHOD_HEADER: &str = "Header";
 
+static DNS_SERVICE_HETZNER_ROBOT: &str = "HetznerRobot";
+static DNS_SERVICE_HETZNER_DNS: &str = "HetznerDns";
+static DNS_SERVICE_RFC2136: &str = "Rfc2136";
+
+static PGP_SIGNER_GPG: &str = "Gpg";
+static PGP_SIGNER_NATIVE: &str = "Native";
+
+static DIGEST_ALGO_SHA256: &str = "SHA256";
+static DIGEST_ALGO_SHA512: &str = "SHA512";
+
+static GPG_DEFAULT_BINARY: &str = "gpg";
+const GPG_DEFAULT_EXPIRY_WARNING_DAYS: u64 = 30;
+const GPG_DEFAULT_TIMEOUT: u64 = 30;
+
+static HETZNER_DNS_DEFAULT_API_URL: &str = "https://dns.hetzner.com/api/v1";
+
+const RFC2136_DEFAULT_TTL: u32 = 60;
+
+const DEFAULT_WORKER_THREADS: usize = 4;
+const DEFAULT_AUTH_MAX_FAILURES: u32 = 10;
+const DEFAULT_AUTH_FAILURE_WINDOW: u64 = 600;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+const RETRY_DEFAULT_INITIAL_DELAY: u64 = 60;
+const RETRY_DEFAULT_MAX_DELAY: u64 = 3600;
+
 #[derive(Deserialize, Debug)]
 struct RawConfig {
-    from_addr: String,
-    to_addr: String,
-    smtp_host: String,
-    smtp_username: String,
-    smtp_password: String,
-    smtp_helo_name: String,
-    pgp_key: String,
-    domain: String,
-    hetzner_user: String,
+    dns_service: Option<String>,
+    from_addr: Option<String>,
+    to_addr: Option<String>,
+    smtp_host: Option<String>,
+    smtp_username: Option<String>,
+    smtp_password: Option<String>,
+    smtp_helo_name: Option<String>,
+    pgp_signer: Option<String>,
+    pgp_key: Option<String>,
+    pgp_key_file: Option<String>,
+    pgp_passphrase_file: Option<String>,
+    pgp_passphrase_credential: Option<String>,
+    gpg_binary: Option<String>,
+    gpg_homedir: Option<String>,
+    gpg_digest_algo: Option<String>,
+    gpg_expiry_warning_days: Option<u64>,
+    gpg_timeout: Option<u64>,
+    hetzner_user: Option<String>,
+    template: Option<String>,
+    domains: Option<Vec<RawDomainConfig>>,
+    outbox_file: Option<String>,
+    retry_initial_delay: Option<u64>,
+    retry_max_delay: Option<u64>,
+    hetzner_dns_api_token: Option<String>,
+    hetzner_dns_api_url: Option<String>,
+    hetzner_dns_zone: Option<String>,
+    hetzner_dns_ttl: Option<u32>,
+    rfc2136_server: Option<String>,
+    rfc2136_zone: Option<String>,
+    rfc2136_ttl: Option<u32>,
+    rfc2136_tsig_key_name: Option<String>,
+    rfc2136_tsig_secret: Option<String>,
+    domain: Option<String>,
     server_addr: String,
-    http_auth_user: String,
-    http_auth_password: String,
+    tls_cert_file: Option<String>,
+    tls_key_file: Option<String>,
+    http_auth_user: Option<String>,
+    http_auth_password: Option<String>,
+    http_auth_password_hash: Option<String>,
+    users: Option<Vec<RawUserConfig>>,
+    users_file: Option<String>,
     ip_resolv_method: String,
     ip_header: Option<String>,
-    template: String,
+    ip_peer_fallback: Option<bool>,
+    trusted_proxies: Option<Vec<String>>,
+    deny_bogons: Option<bool>,
+    denied_networks: Option<Vec<String>>,
+    state_file: Option<String>,
+    worker_threads: Option<usize>,
+    min_update_interval: Option<u64>,
+    auth_max_failures: Option<u32>,
+    auth_failure_window: Option<u64>,
+    shutdown_timeout: Option<u64>,
+}
+
+/// An entry of the `[[domains]]` table. Unset values fall back to the top level settings.
+#[derive(Deserialize, Debug)]
+struct RawDomainConfig {
+    domain: String,
+    hetzner_user: Option<String>,
+    template: Option<String>,
+    allowed_clients: Option<Vec<String>>,
+}
+
+/// An entry of the `[[users]]` table.
+#[derive(Deserialize, Debug)]
+struct RawUserConfig {
+    name: String,
+    password: Option<String>,
+    password_hash: Option<String>,
+    hostnames: Vec<String>,
+    allowed_networks: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawUsersFile {
+    users: Vec<RawUserConfig>,
+}
+
+fn get_password(plain: &Option<String>, hash: &Option<String>, name: &str) -> Result<Password> {
+    match (plain, hash) {
+        (Some(plain), None) => Ok(Password::Plain(plain.clone())),
+        (None, Some(hash)) => Password::from_hash(hash),
+        (Some(_), Some(_)) => Err(format!("{0} and {0}_HASH are mutually exclusive.", name).into()),
+        (None, None) => Err(format!("{} not set.", name).into()),
+    }
+}
+
+/// Parses a network in CIDR notation. A single address is a network of its own.
+fn parse_network(network: &str) -> Result<IpNet> {
+    use std::net::IpAddr;
+
+    let network = network.trim();
+    network
+        .parse()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .chain_err(|| format!("Invalid network {}", network))
+}
+
+fn parse_networks(networks: &Option<Vec<String>>) -> Result<Vec<IpNet>> {
+    networks
+        .iter()
+        .flat_map(|networks| networks.iter())
+        .map(|network| parse_network(network))
+        .collect()
+}
+
+fn required(value: &Option<String>, name: &str) -> Result<String> {
+    match value {
+        Some(value) => Ok(value.clone()),
+        None => Err(format!("{} not set.", name).into()),
+    }
 }
 
 impl RawConfig {
@@ -42,22 +170,60 @@ impl RawConfig {
         from_str(&config).chain_err(|| "Error parsing config file")
     }
 
-    fn get_template(&self) -> Result<Template> {
-        use std::fs;
-        use std::io::Read;
+    fn get_domain(&self, raw: &RawDomainConfig) -> Result<DomainConfig> {
+        let template = match raw.template.as_ref().or_else(|| self.template.as_ref()) {
+            Some(path) => Some(read_template(path).chain_err(|| "Error evaluating template")?),
+            None => None,
+        };
+
+        Ok(DomainConfig {
+            name: raw.domain.trim_end_matches('.').to_lowercase(),
+            hetzner_user: raw.hetzner_user.clone().or_else(|| self.hetzner_user.clone()),
+            template,
+            allowed_clients: raw.allowed_clients.clone().unwrap_or_default(),
+        })
+    }
 
-        let mut file = fs::File::open(&self.template).chain_err(|| "Error opening template")?;
-        let mut buffer = String::new();
-        file.read_to_string(&mut buffer)
-            .chain_err(|| "Error reading template to String")?;
+    fn get_domains(&self) -> Result<Vec<DomainConfig>> {
+        let domains = match (&self.domains, &self.domain) {
+            (Some(domains), None) => domains
+                .iter()
+                .map(|raw| {
+                    self.get_domain(raw)
+                        .chain_err(|| format!("Error configuring domain {}", raw.domain))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            (None, Some(domain)) => vec![self.get_domain(&RawDomainConfig {
+                domain: domain.clone(),
+                hetzner_user: None,
+                template: None,
+                allowed_clients: None,
+            })?],
+            (Some(_), Some(_)) => bail!("DOMAIN and [[domains]] are mutually exclusive."),
+            (None, None) => bail!("DOMAIN not set."),
+        };
 
-        Ok(Template::from(&buffer as &str))
+        if domains.is_empty() {
+            bail!("[[domains]] is empty.");
+        }
+
+        for (i, domain) in domains.iter().enumerate() {
+            if domains[..i].iter().any(|d| d.name == domain.name) {
+                bail!("Domain {} is configured twice.", domain.name);
+            }
+        }
+
+        Ok(domains)
     }
 
     fn get_ip_resolv(&self) -> Result<IpResolvMethod> {
         match self.ip_resolv_method {
             ref m if m == IP_RESOLV_METHOD_DYNDNS2 => Ok(IpResolvMethod::DynDns2),
             ref m if m == IP_RESOLV_METHOD_HEADER => match &self.ip_header {
+                // anyone reaching the daemon directly could forge the header
+                Some(_) if self.trusted_proxies.as_ref().map_or(true, Vec::is_empty) => {
+                    Err("TRUSTED_PROXIES not set, IP_HEADER is only accepted from them.".into())
+                }
                 Some(header_name) => Ok(IpResolvMethod::Header(header_name.clone())),
                 None => Err("IP_HEADER not set.".into()),
             },
@@ -67,6 +233,228 @@ impl RawConfig {
             ).into()),
         }
     }
+
+    fn get_users(&self, domains: &[DomainConfig]) -> Result<Vec<UserConfig>> {
+        let mut users = Vec::new();
+
+        let has_password = self.http_auth_password.is_some() || self.http_auth_password_hash.is_some();
+
+        match self.http_auth_user {
+            Some(ref name) => users.push(UserConfig {
+                name: name.clone(),
+                password: get_password(
+                    &self.http_auth_password,
+                    &self.http_auth_password_hash,
+                    "HTTP_AUTH_PASSWORD",
+                )?,
+                hostnames: domains.iter().map(|d| d.name.clone()).collect(),
+                allowed_networks: Vec::new(),
+            }),
+            None if has_password => bail!("HTTP_AUTH_USER not set."),
+            None => {}
+        }
+
+        let mut raw_users: Vec<&RawUserConfig> = self.users.iter().flat_map(|u| u.iter()).collect();
+
+        let users_file = match self.users_file {
+            Some(ref path) => Some(
+                read_users_file(path).chain_err(|| format!("Error reading users from {}", path))?,
+            ),
+            None => None,
+        };
+        raw_users.extend(users_file.iter().flat_map(|f| f.users.iter()));
+
+        for raw in raw_users {
+            let hostnames: Vec<String> = raw.hostnames
+                .iter()
+                .map(|h| h.trim_end_matches('.').to_lowercase())
+                .collect();
+
+            if let Some(unknown) = hostnames.iter().find(|h| !domains.iter().any(|d| d.name == **h)) {
+                bail!("User {} lists {}, which isn't a managed domain.", raw.name, unknown);
+            }
+
+            let password = get_password(&raw.password, &raw.password_hash, "password")
+                .chain_err(|| format!("Error reading password of user {}", raw.name))?;
+
+            let allowed_networks = parse_networks(&raw.allowed_networks)
+                .chain_err(|| format!("Error parsing allowed networks of user {}", raw.name))?;
+
+            users.push(UserConfig {
+                name: raw.name.clone(),
+                password,
+                hostnames,
+                allowed_networks,
+            });
+        }
+
+        if users.is_empty() {
+            bail!("No users configured. Set HTTP_AUTH_USER and HTTP_AUTH_PASSWORD, [[users]] or USERS_FILE.");
+        }
+
+        for (i, user) in users.iter().enumerate() {
+            if users[..i].iter().any(|u| u.name == user.name) {
+                bail!("User {} is configured twice.", user.name);
+            }
+        }
+
+        Ok(users)
+    }
+
+    fn get_tls(&self) -> Result<Option<TlsConfig>> {
+        match (&self.tls_cert_file, &self.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Ok(Some(TlsConfig {
+                cert_file: cert_file.clone(),
+                key_file: key_file.clone(),
+            })),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err("TLS_KEY_FILE not set.".into()),
+            (None, Some(_)) => Err("TLS_CERT_FILE not set.".into()),
+        }
+    }
+
+    fn get_robot(&self) -> Result<RobotConfig> {
+        Ok(RobotConfig {
+            from_addr: required(&self.from_addr, "FROM_ADDR")?,
+            to_addr: required(&self.to_addr, "TO_ADDR")?,
+            smtp_host: required(&self.smtp_host, "SMTP_HOST")?,
+            smtp_username: required(&self.smtp_username, "SMTP_USERNAME")?,
+            smtp_password: required(&self.smtp_password, "SMTP_PASSWORD")?,
+            smtp_helo_name: required(&self.smtp_helo_name, "SMTP_HELO_NAME")?,
+            signer: self.get_pgp_signer()?,
+            outbox_file: self.outbox_file.clone(),
+            retry_initial_delay: self.retry_initial_delay
+                .unwrap_or(RETRY_DEFAULT_INITIAL_DELAY),
+            retry_max_delay: self.retry_max_delay.unwrap_or(RETRY_DEFAULT_MAX_DELAY),
+        })
+    }
+
+    fn get_pgp_signer(&self) -> Result<PgpSignerConfig> {
+        let passphrase_file = self.get_pgp_passphrase_file(::std::env::var_os("CREDENTIALS_DIRECTORY"))?;
+
+        match self.pgp_signer {
+            None => self.get_gpg(passphrase_file).map(PgpSignerConfig::Gpg),
+            Some(ref s) if s == PGP_SIGNER_GPG => self.get_gpg(passphrase_file).map(PgpSignerConfig::Gpg),
+            Some(ref s) if s == PGP_SIGNER_NATIVE => Ok(PgpSignerConfig::Native {
+                key_file: required(&self.pgp_key_file, "PGP_KEY_FILE")?,
+                passphrase_file,
+            }),
+            _ => Err(format!(
+                "Unknown PGP_SIGNER variant. Supported: {}, {}",
+                PGP_SIGNER_GPG, PGP_SIGNER_NATIVE
+            ).into()),
+        }
+    }
+
+    fn get_gpg(&self, passphrase_file: Option<String>) -> Result<GpgConfig> {
+        Ok(GpgConfig {
+            key: required(&self.pgp_key, "PGP_KEY")?,
+            passphrase_file,
+            binary: self.gpg_binary
+                .clone()
+                .unwrap_or_else(|| GPG_DEFAULT_BINARY.to_owned()),
+            homedir: self.gpg_homedir.clone(),
+            digest_algo: self.get_digest_algo()?,
+            expiry_warning_days: self.gpg_expiry_warning_days
+                .unwrap_or(GPG_DEFAULT_EXPIRY_WARNING_DAYS),
+            timeout: self.gpg_timeout.unwrap_or(GPG_DEFAULT_TIMEOUT),
+        })
+    }
+
+    fn get_digest_algo(&self) -> Result<DigestAlgo> {
+        match self.gpg_digest_algo {
+            None => Ok(DigestAlgo::Sha256),
+            Some(ref s) if s.eq_ignore_ascii_case(DIGEST_ALGO_SHA256) => Ok(DigestAlgo::Sha256),
+            Some(ref s) if s.eq_ignore_ascii_case(DIGEST_ALGO_SHA512) => Ok(DigestAlgo::Sha512),
+            _ => Err(format!(
+                "Unknown GPG_DIGEST_ALGO variant. Supported: {}, {}",
+                DIGEST_ALGO_SHA256, DIGEST_ALGO_SHA512
+            ).into()),
+        }
+    }
+
+    /// Path of the file with the key passphrase. Only the path is kept in the config,
+    /// so the passphrase itself never ends up in the logged config.
+    fn get_pgp_passphrase_file(&self, credentials_directory: Option<OsString>) -> Result<Option<String>> {
+        match (&self.pgp_passphrase_file, &self.pgp_passphrase_credential) {
+            (Some(_), Some(_)) => Err("Only one of PGP_PASSPHRASE_FILE and PGP_PASSPHRASE_CREDENTIAL may be set.".into()),
+            (Some(file), None) => Ok(Some(file.clone())),
+            (None, Some(name)) => {
+                if name.is_empty() || name.contains('/') {
+                    bail!("Invalid PGP_PASSPHRASE_CREDENTIAL {}", name);
+                }
+                let directory = credentials_directory
+                    .ok_or("PGP_PASSPHRASE_CREDENTIAL set, but systemd passed no CREDENTIALS_DIRECTORY.")?;
+                Ok(Some(Path::new(&directory).join(name).to_string_lossy().into_owned()))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn get_hetzner_dns(&self) -> Result<HetznerDnsConfig> {
+        Ok(HetznerDnsConfig {
+            api_token: required(&self.hetzner_dns_api_token, "HETZNER_DNS_API_TOKEN")?,
+            api_url: self.hetzner_dns_api_url
+                .clone()
+                .unwrap_or_else(|| HETZNER_DNS_DEFAULT_API_URL.to_owned()),
+            zone: self.hetzner_dns_zone.clone(),
+            ttl: self.hetzner_dns_ttl,
+        })
+    }
+
+    fn get_rfc2136(&self) -> Result<Rfc2136Config> {
+        Ok(Rfc2136Config {
+            server: required(&self.rfc2136_server, "RFC2136_SERVER")?,
+            zone: self.rfc2136_zone.clone(),
+            ttl: self.rfc2136_ttl.unwrap_or(RFC2136_DEFAULT_TTL),
+            tsig_key_name: required(&self.rfc2136_tsig_key_name, "RFC2136_TSIG_KEY_NAME")?,
+            tsig_secret: required(&self.rfc2136_tsig_secret, "RFC2136_TSIG_SECRET")?,
+        })
+    }
+
+    fn get_dns_service(&self) -> Result<DnsServiceConfig> {
+        match self.dns_service {
+            None => self.get_robot().map(DnsServiceConfig::HetznerRobot),
+            Some(ref s) if s == DNS_SERVICE_HETZNER_ROBOT => {
+                self.get_robot().map(DnsServiceConfig::HetznerRobot)
+            }
+            Some(ref s) if s == DNS_SERVICE_HETZNER_DNS => {
+                self.get_hetzner_dns().map(DnsServiceConfig::HetznerDns)
+            }
+            Some(ref s) if s == DNS_SERVICE_RFC2136 => {
+                self.get_rfc2136().map(DnsServiceConfig::Rfc2136)
+            }
+            _ => Err(format!(
+                "Unknown DNS_SERVICE variant. Supported: {}, {}, {}",
+                DNS_SERVICE_HETZNER_ROBOT, DNS_SERVICE_HETZNER_DNS, DNS_SERVICE_RFC2136
+            ).into()),
+        }
+    }
+}
+
+fn read_template(path: &str) -> Result<Template> {
+    use std::fs;
+    use std::io::Read;
+
+    let mut file = fs::File::open(path).chain_err(|| "Error opening template")?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer)
+        .chain_err(|| "Error reading template to String")?;
+
+    Ok(Template::from(&buffer as &str))
+}
+
+fn read_users_file(path: &str) -> Result<RawUsersFile> {
+    use std::fs;
+    use std::io::Read;
+    use toml::de::from_str;
+
+    let mut file = fs::File::open(path).chain_err(|| "Error opening users file")?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer)
+        .chain_err(|| "Error reading users file")?;
+
+    from_str(&buffer).chain_err(|| "Error parsing users file")
 }
 
 #[derive(Debug, Clone)]
@@ -75,22 +463,161 @@ pub enum IpResolvMethod {
     DynDns2,
 }
 
+/// Hash algorithm of the signatures created by gpg.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestAlgo {
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlgo {
+    /// Name as understood by `gpg --digest-algo`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DigestAlgo::Sha256 => DIGEST_ALGO_SHA256,
+            DigestAlgo::Sha512 => DIGEST_ALGO_SHA512,
+        }
+    }
+}
+
+/// Settings for signing with the `gpg` binary.
+#[derive(Debug, Clone)]
+pub struct GpgConfig {
+    /// Key from the keyring
+    pub key: String,
+    /// File with the passphrase of a protected key
+    pub passphrase_file: Option<String>,
+    /// Path of the binary, looked up in `PATH` without slash
+    pub binary: String,
+    /// Keyring directory instead of `~/.gnupg`
+    pub homedir: Option<String>,
+    pub digest_algo: DigestAlgo,
+    /// Days before the expiration of the key from which a warning is logged at startup
+    pub expiry_warning_days: u64,
+    /// Seconds after which a hanging gpg is killed
+    pub timeout: u64,
+}
+
+/// How update mails are signed.
+#[derive(Debug, Clone)]
+pub enum PgpSignerConfig {
+    /// With the `gpg` binary
+    Gpg(GpgConfig),
+    /// In-process with the armored secret key in `key_file`, unlocked with the
+    /// passphrase in `passphrase_file`
+    Native {
+        key_file: String,
+        passphrase_file: Option<String>,
+    },
+}
+
+/// Settings for the e-mail workflow of Hetzner's Domain Registration Robot.
 #[derive(Debug)]
-pub struct Config {
+pub struct RobotConfig {
     pub from_addr: String,
     pub to_addr: String,
     pub smtp_host: String,
     pub smtp_username: String,
     pub smtp_password: String,
     pub smtp_helo_name: String,
-    pub pgp_key: String,
-    pub domain: String,
-    pub hetzner_user: String,
+    pub signer: PgpSignerConfig,
+    pub outbox_file: Option<String>,
+    pub retry_initial_delay: u64,
+    pub retry_max_delay: u64,
+}
+
+/// Settings for the Hetzner DNS Console API.
+#[derive(Debug)]
+pub struct HetznerDnsConfig {
+    pub api_token: String,
+    pub api_url: String,
+    pub zone: Option<String>,
+    pub ttl: Option<u32>,
+}
+
+/// Settings for RFC 2136 dynamic updates signed with TSIG (HMAC-SHA256).
+#[derive(Debug)]
+pub struct Rfc2136Config {
+    pub server: String,
+    pub zone: Option<String>,
+    pub ttl: u32,
+    pub tsig_key_name: String,
+    pub tsig_secret: String,
+}
+
+#[derive(Debug)]
+pub enum DnsServiceConfig {
+    HetznerRobot(RobotConfig),
+    HetznerDns(HetznerDnsConfig),
+    Rfc2136(Rfc2136Config),
+}
+
+/// PEM encoded certificate chain and PKCS #8 private key for HTTPS.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// A domain managed by the daemon.
+#[derive(Debug)]
+pub struct DomainConfig {
+    /// Lower case name without trailing dot
+    pub name: String,
+    /// Robot user, only used by the `HetznerRobot` backend
+    pub hetzner_user: Option<String>,
+    /// Zonefile template, only used by the `HetznerRobot` backend
+    pub template: Option<Template>,
+    /// HTTP users allowed to update the domain. Empty allows every user.
+    pub allowed_clients: Vec<String>,
+}
+
+impl DomainConfig {
+    pub fn allows(&self, user: &str) -> bool {
+        self.allowed_clients.is_empty() || self.allowed_clients.iter().any(|c| c == user)
+    }
+}
+
+/// A client which may send updates.
+#[derive(Debug, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: Password,
+    /// Lower case hostnames without trailing dot
+    pub hostnames: Vec<String>,
+    /// Networks the published addresses must be part of. Empty allows every address.
+    pub allowed_networks: Vec<IpNet>,
+}
+
+impl UserConfig {
+    pub fn may_update(&self, hostname: &str) -> bool {
+        self.hostnames.iter().any(|h| same_host(h, hostname))
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub domains: Vec<DomainConfig>,
     pub server_addr: String,
-    pub http_auth_user: String,
-    pub http_auth_password: String,
+    pub tls: Option<TlsConfig>,
+    pub users: Vec<UserConfig>,
     pub ip_resolv: IpResolvMethod,
-    pub template: Template,
+    /// Use the peer address if the request contains none
+    pub ip_peer_fallback: bool,
+    /// Proxies whose forwarding headers are trusted
+    pub trusted_proxies: Vec<IpNet>,
+    /// Networks whose addresses are never published
+    pub denied_networks: Vec<IpNet>,
+    pub dns_service: DnsServiceConfig,
+    pub state_file: Option<String>,
+    pub worker_threads: usize,
+    pub min_update_interval: u64,
+    /// Failed logins after which a client is locked out, 0 disables the lockout
+    pub auth_max_failures: u32,
+    /// Seconds in which the failures are counted and for which a client is locked out
+    pub auth_failure_window: u64,
+    /// Seconds pending requests may take to finish after SIGTERM or SIGINT
+    pub shutdown_timeout: u64,
 }
 
 impl Config {
@@ -101,28 +628,262 @@ impl Config {
                 .chain_err(|| format!("Error reading config from {}", path.to_string_lossy())),
         }?;
 
-        let template = raw_config
-            .get_template()
-            .chain_err(|| "Error evaluating template")?;
+        let dns_service = raw_config
+            .get_dns_service()
+            .chain_err(|| "Error configuring dns service")?;
         let ip_resolv = raw_config
             .get_ip_resolv()
             .chain_err(|| "Error parsing ip resolution")?;
+        let domains = raw_config
+            .get_domains()
+            .chain_err(|| "Error configuring domains")?;
+        let users = raw_config
+            .get_users(&domains)
+            .chain_err(|| "Error configuring users")?;
+        let tls = raw_config.get_tls().chain_err(|| "Error configuring tls")?;
+        let trusted_proxies = parse_networks(&raw_config.trusted_proxies)
+            .chain_err(|| "Error parsing trusted proxies")?;
+        let mut denied_networks = parse_networks(&raw_config.denied_networks)
+            .chain_err(|| "Error parsing denied networks")?;
+        if raw_config.deny_bogons.unwrap_or(true) {
+            denied_networks.extend(bogons());
+        }
 
         Ok(Config {
-            from_addr: raw_config.from_addr,
-            to_addr: raw_config.to_addr,
-            smtp_host: raw_config.smtp_host,
-            smtp_username: raw_config.smtp_username,
-            smtp_password: raw_config.smtp_password,
-            smtp_helo_name: raw_config.smtp_helo_name,
-            pgp_key: raw_config.pgp_key,
-            domain: raw_config.domain,
-            hetzner_user: raw_config.hetzner_user,
+            domains,
             server_addr: raw_config.server_addr,
-            http_auth_user: raw_config.http_auth_user,
-            http_auth_password: raw_config.http_auth_password,
+            tls,
+            users,
             ip_resolv,
-            template,
+            ip_peer_fallback: raw_config.ip_peer_fallback.unwrap_or(false),
+            trusted_proxies,
+            denied_networks,
+            dns_service,
+            state_file: raw_config.state_file,
+            worker_threads: raw_config.worker_threads.unwrap_or(DEFAULT_WORKER_THREADS),
+            min_update_interval: raw_config.min_update_interval.unwrap_or(0),
+            auth_max_failures: raw_config
+                .auth_max_failures
+                .unwrap_or(DEFAULT_AUTH_MAX_FAILURES),
+            auth_failure_window: raw_config
+                .auth_failure_window
+                .unwrap_or(DEFAULT_AUTH_FAILURE_WINDOW),
+            shutdown_timeout: raw_config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(extra: &str) -> RawConfig {
+        use toml::de::from_str;
+
+        from_str(&format!(
+            r#"
+            server_addr = "127.0.0.1:0"
+            ip_resolv_method = "DynDns2"
+            hetzner_user = "default"
+            {}
+            "#,
+            extra
+        )).unwrap()
+    }
+
+    #[test]
+    fn domain_entries_fall_back_to_top_level() {
+        let domains = raw(
+            r#"
+            [[domains]]
+            domain = "Example.com."
+
+            [[domains]]
+            domain = "example.org"
+            hetzner_user = "other"
+            template = "res/zonefile.tpl"
+            allowed_clients = ["router"]
+            "#,
+        ).get_domains()
+            .unwrap();
+
+        assert_eq!(domains[0].name, "example.com");
+        assert_eq!(domains[0].hetzner_user, Some("default".to_owned()));
+        assert!(domains[0].template.is_none());
+        assert!(domains[0].allows("anyone"));
+
+        assert_eq!(domains[1].hetzner_user, Some("other".to_owned()));
+        assert!(domains[1].template.is_some());
+        assert!(domains[1].allows("router"));
+        assert!(!domains[1].allows("anyone"));
+    }
+
+    #[test]
+    fn single_domain_or_table() {
+        assert_eq!(raw(r#"domain = "example.com""#).get_domains().unwrap().len(), 1);
+        assert!(raw("").get_domains().is_err());
+        assert!(raw("domains = []").get_domains().is_err());
+        assert!(
+            raw(
+                r#"
+                domain = "example.com"
+                [[domains]]
+                domain = "example.org"
+                "#
+            ).get_domains()
+                .is_err()
+        );
+        assert!(
+            raw(
+                r#"
+                [[domains]]
+                domain = "example.com"
+                [[domains]]
+                domain = "EXAMPLE.com"
+                "#
+            ).get_domains()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn users_are_restricted_to_managed_domains() {
+        let mut config = raw(
+            r#"
+            http_auth_user = "user"
+            http_auth_password = "pass"
+
+            [[domains]]
+            domain = "example.com"
+            [[domains]]
+            domain = "example.org"
+
+            [[users]]
+            name = "router"
+            password_hash = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ="
+            hostnames = ["Example.org."]
+            "#,
+        );
+        let domains = config.get_domains().unwrap();
+        let users = config.get_users(&domains).unwrap();
+
+        assert!(users[0].may_update("example.com"));
+        assert!(users[0].may_update("example.org"));
+        assert!(!users[1].may_update("example.com"));
+        assert!(users[1].may_update("example.org"));
+        assert!(users[0].password.verify("pass"));
+        assert!(users[1].password.verify("secret"));
+
+        config.users.as_mut().unwrap()[0].hostnames.push("example.net".to_owned());
+        assert!(config.get_users(&domains).is_err());
+
+        config.users.as_mut().unwrap()[0].name = "user".to_owned();
+        config.users.as_mut().unwrap()[0].hostnames.pop();
+        assert!(config.get_users(&domains).is_err());
+
+        config.users = None;
+        config.http_auth_user = None;
+        config.http_auth_password = None;
+        assert!(config.get_users(&domains).is_err());
+    }
+
+    #[test]
+    fn passphrase_file_or_credential() {
+        assert_eq!(raw("").get_pgp_passphrase_file(None).unwrap(), None);
+
+        let config = raw(r#"pgp_passphrase_file = "/etc/dyndns/passphrase""#);
+        assert_eq!(
+            config.get_pgp_passphrase_file(None).unwrap(),
+            Some("/etc/dyndns/passphrase".to_owned())
+        );
+
+        let config = raw(r#"pgp_passphrase_credential = "pgp-passphrase""#);
+        assert_eq!(
+            config
+                .get_pgp_passphrase_file(Some("/run/credentials/dyndns.service".into()))
+                .unwrap(),
+            Some("/run/credentials/dyndns.service/pgp-passphrase".to_owned())
+        );
+        assert!(config.get_pgp_passphrase_file(None).is_err());
+        assert!(
+            raw(r#"pgp_passphrase_credential = "../passphrase""#)
+                .get_pgp_passphrase_file(Some("/run/credentials/dyndns.service".into()))
+                .is_err()
+        );
+        assert!(
+            raw(
+                r#"
+                pgp_passphrase_file = "/etc/dyndns/passphrase"
+                pgp_passphrase_credential = "pgp-passphrase"
+                "#
+            ).get_pgp_passphrase_file(None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn gpg_settings() {
+        let config = raw(r#"pgp_key = "0000""#);
+        match config.get_pgp_signer().unwrap() {
+            PgpSignerConfig::Gpg(gpg) => {
+                assert_eq!(gpg.binary, "gpg");
+                assert_eq!(gpg.homedir, None);
+                assert_eq!(gpg.digest_algo, DigestAlgo::Sha256);
+                assert_eq!(gpg.expiry_warning_days, 30);
+                assert_eq!(gpg.timeout, 30);
+            }
+            signer => panic!("unexpected signer {:?}", signer),
+        }
+
+        let config = raw(
+            r#"
+            pgp_key = "0000"
+            gpg_binary = "/usr/bin/gpg2"
+            gpg_homedir = "/var/lib/dyndns-daemon/gnupg"
+            gpg_digest_algo = "sha512"
+            "#,
+        );
+        match config.get_pgp_signer().unwrap() {
+            PgpSignerConfig::Gpg(gpg) => {
+                assert_eq!(gpg.binary, "/usr/bin/gpg2");
+                assert_eq!(gpg.homedir, Some("/var/lib/dyndns-daemon/gnupg".to_owned()));
+                assert_eq!(gpg.digest_algo, DigestAlgo::Sha512);
+            }
+            signer => panic!("unexpected signer {:?}", signer),
+        }
+
+        assert!(raw(r#"gpg_digest_algo = "SHA1""#).get_digest_algo().is_err());
+    }
+
+    #[test]
+    fn sample_config_loads() {
+        let config = Config::from_source(&::ConfigSource::File("res/config.toml".into())).unwrap();
+        assert_eq!(config.domains[0].name, "example.com");
+        assert_eq!(config.trusted_proxies.len(), 2);
+    }
+
+    #[test]
+    fn header_needs_trusted_proxies() {
+        let mut config = raw(r#"ip_header = "X-Forwarded-For""#);
+        config.ip_resolv_method = IP_RESOLV_METHOD_HEADER.to_owned();
+        assert!(config.get_ip_resolv().is_err());
+
+        config.trusted_proxies = Some(vec![]);
+        assert!(config.get_ip_resolv().is_err());
+
+        config.trusted_proxies = Some(vec!["192.0.2.1".to_owned()]);
+        match config.get_ip_resolv().unwrap() {
+            IpResolvMethod::Header(name) => assert_eq!(name, "X-Forwarded-For"),
+            IpResolvMethod::DynDns2 => panic!("expected Header"),
+        }
+    }
+
+    #[test]
+    fn networks() {
+        assert_eq!(parse_network("10.0.0.0/8").unwrap(), "10.0.0.0/8".parse::<IpNet>().unwrap());
+        assert_eq!(parse_network(" 192.0.2.1").unwrap(), "192.0.2.1/32".parse::<IpNet>().unwrap());
+        assert_eq!(parse_network("2001:db8::1").unwrap(), "2001:db8::1/128".parse::<IpNet>().unwrap());
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("localhost").is_err());
+    }
+}
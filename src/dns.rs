@@ -1,13 +1,59 @@
 use slog::Logger;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use config::Config;
+use config::{DomainConfig, RobotConfig};
 use errors::*;
+use metrics::{timed, Metrics};
 use openpgp::SignedMessageBuilder;
-use template::Template;
+use outbox::Outbox;
+use state::StateStore;
+use status::LastErrors;
+use template::{Addresses, Template};
+
+const RETRY_POLL_SECS: u64 = 10;
+const SMTP_CHECK_TIMEOUT_SECS: u64 = 5;
+/// Time the SMTP server has to accept a mail, lettre itself never gives up.
+const SMTP_SEND_TIMEOUT_SECS: u64 = 60;
+
+/// Outcome of an update accepted by a DNS service.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Update {
+    /// The address has been published
+    Published,
+    /// Delivery failed and is retried in the background, which records the address
+    /// as published once it has been delivered
+    Queued,
+}
 
 pub trait DnsService {
-    fn update(&self, addr: &Ipv4Addr) -> Result<()>;
+    fn update(&self, addr: &IpAddr) -> Result<Update>;
+
+    /// Checks whether the tools and servers the service depends on are usable.
+    fn check(&self) -> Vec<(&'static str, Result<()>)> {
+        Vec::new()
+    }
+}
+
+impl<T: DnsService + ?Sized> DnsService for Box<T> {
+    fn update(&self, addr: &IpAddr) -> Result<Update> {
+        (**self).update(addr)
+    }
+
+    fn check(&self) -> Vec<(&'static str, Result<()>)> {
+        (**self).check()
+    }
+}
+
+impl<T: DnsService + ?Sized> DnsService for Arc<T> {
+    fn update(&self, addr: &IpAddr) -> Result<Update> {
+        (**self).update(addr)
+    }
+
+    fn check(&self) -> Vec<(&'static str, Result<()>)> {
+        (**self).check()
+    }
 }
 
 pub struct HetznerClient<S> {
@@ -22,31 +68,150 @@ pub struct HetznerClient<S> {
     domain: String,
     template: Template,
     helo_name: String,
+    smtp_timeout: Duration,
+    addresses: Mutex<Addresses>,
+    state: Arc<StateStore>,
+    outbox: Arc<Outbox>,
+    metrics: Arc<Metrics>,
+    errors: Arc<LastErrors>,
 }
 
 impl<S: SignedMessageBuilder> HetznerClient<S> {
-    pub fn new(parent_logger: &Logger, config: &Config, signed_message_builder: S) -> Self {
-        let logger = parent_logger.new(o!("dns-service" => "hetzner"));
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        parent_logger: &Logger,
+        domain: &DomainConfig,
+        config: &RobotConfig,
+        signed_message_builder: S,
+        state: Arc<StateStore>,
+        outbox: Arc<Outbox>,
+        metrics: Arc<Metrics>,
+        errors: Arc<LastErrors>,
+    ) -> Result<Self> {
+        let logger = parent_logger.new(o!(
+            "dns-service" => "hetzner",
+            "domain" => domain.name.clone()
+        ));
 
-        HetznerClient {
+        let hetzner_user = domain
+            .hetzner_user
+            .clone()
+            .ok_or_else(|| format!("HETZNER_USER not set for {}.", domain.name))?;
+        let template = domain
+            .template
+            .clone()
+            .ok_or_else(|| format!("TEMPLATE not set for {}.", domain.name))?;
+
+        let published = state.get(&domain.name)?;
+        let addresses = Addresses {
+            v4: published.ipv4,
+            v6: published.ipv6,
+        };
+
+        Ok(HetznerClient {
             logger,
             to_addr: config.to_addr.clone(),
             from_addr: config.from_addr.clone(),
             smtp_host: config.smtp_host.clone(),
             username: config.smtp_username.clone(),
             password: config.smtp_password.clone(),
-            hetzner_user: config.hetzner_user.clone(),
-            domain: config.domain.clone(),
+            hetzner_user,
+            domain: domain.name.clone(),
             signed_message_builder,
-            template: config.template.clone(),
+            template,
             helo_name: config.smtp_helo_name.clone(),
+            smtp_timeout: Duration::from_secs(SMTP_SEND_TIMEOUT_SECS),
+            addresses: Mutex::new(addresses),
+            state,
+            outbox,
+            metrics,
+            errors,
+        })
+    }
+
+    /// Retries the delivery of queued updates of this domain whose backoff has elapsed.
+    pub fn retry_pending(&self) -> Result<()> {
+        use chrono::Utc;
+
+        let now = Utc::now().timestamp();
+
+        let due = self.outbox.due(now)?;
+        for (domain, _) in due.into_iter().filter(|&(ref d, _)| *d == self.domain) {
+            // rendered under the lock like an update, but sent without it
+            let (addresses, text) = {
+                let mut addresses = self.addresses
+                    .lock()
+                    .map_err(|_| "Address cache lock poisoned")?;
+
+                // an update may have delivered a newer address since the snapshot,
+                // which removed the entry; it is only read again under the lock
+                let pending = match self.outbox.get(&domain)? {
+                    Some(ref pending) if pending.next_attempt <= now => pending.clone(),
+                    _ => continue,
+                };
+
+                info!(self.logger, "retrying queued update";
+                    "domain" => domain.clone(),
+                    "addr" => format!("{}", pending),
+                    "attempt" => pending.attempts + 1);
+
+                for addr in pending.addresses() {
+                    addresses.set(&addr);
+                }
+                (*addresses, self.build_mail_text(&addresses))
+            };
+
+            let result = text
+                .chain_err(|| "Error building email text")
+                .and_then(|text| self.send_mail(&text));
+
+            match result {
+                Ok(()) => {
+                    self.outbox.delivered(&domain, &addresses.to_vec())?;
+                    info!(self.logger, "delivered queued update";
+                        "domain" => domain,
+                        "pending" => self.outbox.pending_count()?);
+                    self.record_delivered(&addresses)?;
+                }
+                Err(e) => {
+                    log_error(&self.logger, &e);
+                    self.errors.record(&domain, &e);
+                    if let Some(pending) = self.outbox.failed(&domain, now)? {
+                        warn!(self.logger, "queued update failed again";
+                            "domain" => domain,
+                            "attempts" => pending.attempts,
+                            "next_attempt_in" => pending.next_attempt - now);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records the delivered `addresses` which differ from the state as published.
+    fn record_delivered(&self, addresses: &Addresses) -> Result<()> {
+        let published = self.state.get(&self.domain)?;
+
+        for addr in addresses.to_vec() {
+            if published.address(&addr) != Some(addr) {
+                self.state
+                    .record(&self.domain, &addr)
+                    .chain_err(|| "Error recording delivered update")?;
+            }
         }
+
+        Ok(())
     }
 
+    /// Sends the mail on a thread of its own, as lettre has no socket timeouts. A stalled
+    /// SMTP server only blocks that thread, which is left behind after the timeout.
     fn send_mail(&self, text: &str) -> Result<()> {
         use lettre::email::EmailBuilder;
         use lettre::transport::smtp::SmtpTransportBuilder;
         use lettre::transport::EmailTransport;
+        use std::sync::mpsc::RecvTimeoutError;
+        use std::thread;
 
         let email = EmailBuilder::new()
             .to(self.to_addr.as_ref())
@@ -63,11 +228,46 @@ impl<S: SignedMessageBuilder> HetznerClient<S> {
             .connection_reuse(true)
             .build();
 
-        transport.send(email).chain_err(|| "Error sending mail")?;
-        Ok(())
+        let (sent, result) = mpsc::channel();
+        thread::Builder::new()
+            .name("smtp".to_owned())
+            .spawn(move || {
+                let _ = sent.send(transport.send(email));
+            })
+            .chain_err(|| "Error spawning SMTP thread")?;
+
+        match timed(|d| self.metrics.smtp_send(d), || result.recv_timeout(self.smtp_timeout)) {
+            Ok(result) => result.map(|_| ()).chain_err(|| "Error sending mail"),
+            Err(RecvTimeoutError::Timeout) => bail!(
+                "SMTP server {} didn't accept the mail within {} seconds",
+                self.smtp_host,
+                self.smtp_timeout.as_secs()
+            ),
+            Err(RecvTimeoutError::Disconnected) => bail!("SMTP thread panicked"),
+        }
     }
 
-    fn build_mail_text(&self, addr: &Ipv4Addr) -> Result<String> {
+    /// Only checks that the SMTP server accepts connections, a test mail would reach the robot.
+    fn check_smtp(&self) -> Result<()> {
+        use std::net::{TcpStream, ToSocketAddrs};
+
+        let addrs = self.smtp_host
+            .to_socket_addrs()
+            .chain_err(|| format!("Error resolving {}", self.smtp_host))?;
+
+        let mut result = Err(format!("No address found for {}", self.smtp_host).into());
+        for addr in addrs {
+            result = TcpStream::connect_timeout(&addr, Duration::from_secs(SMTP_CHECK_TIMEOUT_SECS))
+                .map(|_| ())
+                .chain_err(|| format!("Error connecting to {}", addr));
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn build_mail_text(&self, addrs: &Addresses) -> Result<String> {
         let mut text = String::new();
         text.push_str(&format!("user: {}\n", self.hetzner_user));
         text.push_str("job: ns\n");
@@ -81,27 +281,232 @@ impl<S: SignedMessageBuilder> HetznerClient<S> {
         let now = Utc::now();
 
         let zonefile = self.template
-            .render(addr, now)
+            .render(addrs, now)
             .chain_err(|| "Error rendering zonefile")?;
         text += &zonefile;
 
         text.push_str("/end\n");
 
-        self.signed_message_builder
-            .sign(&text)
+        timed(|d| self.metrics.gpg_sign(d), || self.signed_message_builder.sign(&text))
             .chain_err(|| "Error signing email")
     }
 }
 
 impl<S: SignedMessageBuilder> DnsService for HetznerClient<S> {
-    fn update(&self, addr: &Ipv4Addr) -> Result<()> {
+    fn update(&self, addr: &IpAddr) -> Result<Update> {
         info!(self.logger, "called with: {}", addr);
 
-        let mail_text = self.build_mail_text(addr)
-            .chain_err(|| "Error building email text")?;
+        // the zonefile would be sent without the address, yet it would count as published
+        if !self.template.includes(addr) {
+            bail!("The template of {} has no placeholder for {}", self.domain, addr);
+        }
 
-        self.send_mail(&mail_text).chain_err(|| "Foo")?;
+        // The zonefile contains the records of both address families, so the
+        // address of the other family is taken from the last update. It is rendered
+        // under the lock, so concurrent updates build on each other, but sent without
+        // it, so a slow SMTP server doesn't hold up the other updates of the domain.
+        let (updated, mail_text) = {
+            let mut addresses = self.addresses
+                .lock()
+                .map_err(|_| "Address cache lock poisoned")?;
+            let mut updated = *addresses;
+            updated.set(addr);
 
-        Ok(())
+            let mail_text = self.build_mail_text(&updated)
+                .chain_err(|| "Error building email text")?;
+            *addresses = updated;
+            (updated, mail_text)
+        };
+
+        if let Err(e) = self.send_mail(&mail_text) {
+            use chrono::Utc;
+
+            log_error(&self.logger, &e);
+            self.errors.record(&self.domain, &e);
+
+            let now = Utc::now().timestamp();
+            let pending = self.outbox
+                .enqueue(&self.domain, addr, now)
+                .chain_err(|| "Error queueing failed update")?;
+
+            warn!(self.logger, "delivery failed, queued update";
+                "domain" => self.domain.clone(),
+                "addr" => format!("{}", addr),
+                "next_attempt_in" => pending.next_attempt - now,
+                "pending" => self.outbox.pending_count()?);
+
+            return Ok(Update::Queued);
+        }
+
+        if let Some(pending) = self.outbox.delivered(&self.domain, &updated.to_vec())? {
+            info!(self.logger, "dropped superseded queued update";
+                "domain" => self.domain.clone(),
+                "addr" => format!("{}", pending));
+        }
+
+        // the zonefile also carried the address of the other family, which may
+        // not have been delivered before; the caller records `addr` itself
+        let other = match *addr {
+            IpAddr::V4(_) => Addresses { v4: None, ..updated },
+            IpAddr::V6(_) => Addresses { v6: None, ..updated },
+        };
+        if let Err(e) = self.record_delivered(&other) {
+            log_error(&self.logger, &e);
+        }
+
+        Ok(Update::Published)
+    }
+
+    fn check(&self) -> Vec<(&'static str, Result<()>)> {
+        let gpg = self.signed_message_builder
+            .sign("dyndns-daemon status check")
+            .map(|_| ())
+            .chain_err(|| "Error signing test message");
+
+        vec![("gpg", gpg), ("smtp", self.check_smtp())]
+    }
+}
+
+/// Handle of the thread retrying queued updates.
+pub struct RetryWorker {
+    stop: mpsc::Sender<()>,
+    stopped: mpsc::Receiver<()>,
+}
+
+impl RetryWorker {
+    /// Stops the worker after the current delivery attempt, waiting at most until `deadline`.
+    /// Returns whether the worker stopped in time.
+    pub fn stop(self, deadline: Instant) -> bool {
+        let _ = self.stop.send(());
+
+        let now = Instant::now();
+        let timeout = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+        self.stopped.recv_timeout(timeout).is_ok()
+    }
+}
+
+/// Retries the queued updates of all `clients`, which share one outbox.
+pub fn spawn_retry_worker<S: SignedMessageBuilder>(clients: Vec<Arc<HetznerClient<S>>>) -> Result<RetryWorker> {
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::thread;
+
+    let (stop, stop_requested) = mpsc::channel();
+    let (notify_stopped, stopped) = mpsc::channel();
+
+    thread::Builder::new()
+        .name("outbox".to_owned())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) =
+                stop_requested.recv_timeout(Duration::from_secs(RETRY_POLL_SECS))
+            {
+                for client in &clients {
+                    if let Err(e) = client.retry_pending() {
+                        log_error(&client.logger, &e);
+                    }
+                }
+            }
+            let _ = notify_stopped.send(());
+        })
+        .chain_err(|| "Error spawning retry worker")?;
+
+    Ok(RetryWorker { stop, stopped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::PgpSignerConfig;
+    use slog;
+
+    struct Unsigned;
+
+    impl SignedMessageBuilder for Unsigned {
+        fn sign(&self, text: &str) -> Result<String> {
+            Ok(text.to_owned())
+        }
+    }
+
+    /// A client whose mails can't be delivered, nothing listens on port 1.
+    fn client(template: &str) -> HetznerClient<Unsigned> {
+        client_of("127.0.0.1:1", template)
+    }
+
+    fn client_of(smtp_host: &str, template: &str) -> HetznerClient<Unsigned> {
+        let domain = DomainConfig {
+            name: "example.com".to_owned(),
+            hetzner_user: Some("user".to_owned()),
+            template: Some(Template::from(template)),
+            allowed_clients: Vec::new(),
+        };
+        let robot = RobotConfig {
+            from_addr: "dyndns@example.com".to_owned(),
+            to_addr: "robot@example.com".to_owned(),
+            smtp_host: smtp_host.to_owned(),
+            smtp_username: "user".to_owned(),
+            smtp_password: "pass".to_owned(),
+            smtp_helo_name: "localhost".to_owned(),
+            signer: PgpSignerConfig::Native {
+                key_file: "res/test/ed25519.asc".to_owned(),
+                passphrase_file: None,
+            },
+            outbox_file: None,
+            retry_initial_delay: 60,
+            retry_max_delay: 3600,
+        };
+
+        HetznerClient::new(
+            &slog::Logger::root(slog::Discard, o!()),
+            &domain,
+            &robot,
+            Unsigned,
+            Arc::new(StateStore::load(None::<&str>).unwrap()),
+            Arc::new(Outbox::load(None::<&str>, 60, 3600).unwrap()),
+            Arc::new(Metrics::default()),
+            Arc::new(LastErrors::default()),
+        ).unwrap()
+    }
+
+    #[test]
+    fn families_missing_in_template_are_rejected() {
+        let client = client("@ IN A {%IP%}\n");
+
+        assert!(client.update(&"2001:db8::1".parse().unwrap()).is_err());
+        assert_eq!(client.outbox.pending_count().unwrap(), 0);
+        assert_eq!(client.update(&"192.0.2.1".parse().unwrap()).unwrap(), Update::Queued);
+    }
+
+    #[test]
+    fn stalled_smtp_server_does_not_block_domain() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        // accepts connections, but never greets
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&server.local_addr().unwrap().to_string(), "@ IN A {%IP%}\n");
+        client.smtp_timeout = Duration::from_secs(1);
+        let client = Arc::new(client);
+
+        let stalled = {
+            let client = client.clone();
+            thread::spawn(move || client.update(&"192.0.2.1".parse().unwrap()))
+        };
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(*client.addresses.try_lock().unwrap(), Addresses {
+            v4: Some("192.0.2.1".parse().unwrap()),
+            v6: None,
+        });
+
+        assert_eq!(stalled.join().unwrap().unwrap(), Update::Queued);
+        assert!(client.errors.get("example.com").is_some());
+        drop(server);
+    }
+
+    #[test]
+    fn first_update_renders_without_other_family() {
+        let client = client("@ IN A {%IP%}\n@ IN AAAA {%IP6%}\n");
+
+        // rendered and signed, only the delivery failed
+        assert_eq!(client.update(&"2001:db8::1".parse().unwrap()).unwrap(), Update::Queued);
+        assert_eq!(client.outbox.pending_count().unwrap(), 1);
     }
 }
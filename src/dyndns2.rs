@@ -0,0 +1,110 @@
+use std::fmt;
+use std::net::IpAddr;
+
+/// Maximum number of hostnames in a single request.
+pub const MAX_HOSTNAMES: usize = 20;
+
+/// Answers of the dyndns2 protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReturnCode {
+    Good(IpAddr),
+    NoChg(IpAddr),
+    BadAuth,
+    BadAgent,
+    NotFqdn,
+    NoHost,
+    NumHost,
+    Abuse,
+    DnsErr,
+    ServerError,
+    BadRequest,
+    BadAddr(IpAddr),
+}
+
+impl ReturnCode {
+    /// The code without the address.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReturnCode::Good(_) => "good",
+            ReturnCode::NoChg(_) => "nochg",
+            ReturnCode::BadAuth => "badauth",
+            ReturnCode::BadAgent => "badagent",
+            ReturnCode::NotFqdn => "notfqdn",
+            ReturnCode::NoHost => "nohost",
+            ReturnCode::NumHost => "numhost",
+            ReturnCode::Abuse => "abuse",
+            ReturnCode::DnsErr => "dnserr",
+            ReturnCode::ServerError => "911",
+            ReturnCode::BadRequest => "badrequest",
+            ReturnCode::BadAddr(_) => "badaddr",
+        }
+    }
+}
+
+impl fmt::Display for ReturnCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReturnCode::Good(ref ip) | ReturnCode::NoChg(ref ip) | ReturnCode::BadAddr(ref ip) => {
+                write!(f, "{} {}", self.name(), ip)
+            }
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+/// Checks whether `hostname` is a syntactically valid, fully qualified domain name.
+pub fn is_fqdn(hostname: &str) -> bool {
+    let hostname = if hostname.ends_with('.') {
+        &hostname[..hostname.len() - 1]
+    } else {
+        hostname
+    };
+
+    let valid_label = |label: &str| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    hostname.len() <= 253 && hostname.split('.').count() >= 2 && hostname.split('.').all(valid_label)
+}
+
+/// Compares hostnames ignoring case and a trailing dot.
+pub fn same_host(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fqdns() {
+        assert!(is_fqdn("example.com"));
+        assert!(is_fqdn("home.example.com."));
+        assert!(is_fqdn("_acme-challenge.example.com"));
+        assert!(!is_fqdn("localhost"));
+        assert!(!is_fqdn(""));
+        assert!(!is_fqdn("example..com"));
+        assert!(!is_fqdn("-home.example.com"));
+        assert!(!is_fqdn("home example.com"));
+        assert!(!is_fqdn("a.b,c.d"));
+    }
+
+    #[test]
+    fn hosts_compare_case_insensitive() {
+        assert!(same_host("Example.COM.", "example.com"));
+        assert!(!same_host("home.example.com", "example.com"));
+    }
+
+    #[test]
+    fn return_codes() {
+        let ip = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(ReturnCode::Good(ip).to_string(), "good 192.0.2.1");
+        assert_eq!(ReturnCode::NoChg(ip).to_string(), "nochg 192.0.2.1");
+        assert_eq!(ReturnCode::ServerError.to_string(), "911");
+    }
+}
@@ -22,4 +22,19 @@ pub fn log_error(logger: &Logger, e: &Error) {
     error!(logger, "{}", message);
 }
 
-error_chain! {}
+error_chain! {
+    errors {
+        GpgSpawn(binary: String) {
+            description("gpg could not be started")
+            display("Error spawning {}", binary)
+        }
+        GpgTimeout(seconds: u64) {
+            description("gpg timed out")
+            display("gpg didn't finish within {} seconds and was killed", seconds)
+        }
+        GpgFailed(status: String, stderr: String) {
+            description("gpg failed")
+            display("gpg failed with {}\nstderr: {}", status, stderr)
+        }
+    }
+}
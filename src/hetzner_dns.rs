@@ -0,0 +1,382 @@
+use reqwest;
+use slog::Logger;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use config::HetznerDnsConfig;
+use dns::{DnsService, Update};
+use errors::*;
+
+static API_TOKEN_HEADER: &str = "Auth-API-Token";
+
+/// Largest page size the API allows.
+const RECORDS_PER_PAGE: &str = "100";
+
+#[derive(Deserialize, Debug)]
+struct Zone {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Zones {
+    zones: Vec<Zone>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Record {
+    id: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Pagination {
+    last_page: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct Meta {
+    pagination: Pagination,
+}
+
+#[derive(Deserialize, Debug)]
+struct Records {
+    records: Vec<Record>,
+    meta: Option<Meta>,
+}
+
+#[derive(Serialize, Debug)]
+struct RecordUpdate<'a> {
+    zone_id: &'a str,
+    #[serde(rename = "type")]
+    record_type: &'a str,
+    name: &'a str,
+    value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u32>,
+}
+
+fn relative_name(domain: &str, zone: &str) -> Result<String> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let zone = zone.trim_end_matches('.').to_lowercase();
+
+    if domain == zone {
+        return Ok("@".to_owned());
+    }
+
+    match domain.len().checked_sub(zone.len() + 1) {
+        Some(end) if domain.ends_with(&zone) && domain[end..].starts_with('.') => {
+            Ok(domain[..end].to_owned())
+        }
+        _ => bail!("Domain {} is not part of zone {}", domain, zone),
+    }
+}
+
+pub struct HetznerDnsClient {
+    logger: Logger,
+    client: reqwest::Client,
+    api_url: String,
+    api_token: String,
+    zone: String,
+    record_name: String,
+    ttl: Option<u32>,
+}
+
+impl HetznerDnsClient {
+    pub fn new(parent_logger: &Logger, domain: &str, config: &HetznerDnsConfig) -> Result<Self> {
+        let logger = parent_logger.new(o!("dns-service" => "hetzner-dns"));
+
+        // without an explicit zone the domain is expected to be the zone apex,
+        // names are compared in lower case like the configured domains
+        let zone = config
+            .zone
+            .as_ref()
+            .map_or(domain, String::as_str)
+            .trim_end_matches('.')
+            .to_lowercase();
+        let record_name = relative_name(domain, &zone)?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .chain_err(|| "Error creating http client")?;
+
+        Ok(HetznerDnsClient {
+            logger,
+            client,
+            api_url: config.api_url.trim_end_matches('/').to_owned(),
+            api_token: config.api_token.clone(),
+            zone,
+            record_name,
+            ttl: config.ttl,
+        })
+    }
+
+    fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<reqwest::Response> {
+        self.client
+            .get(&format!("{}/{}", self.api_url, path))
+            .header(API_TOKEN_HEADER, self.api_token.as_str())
+            .query(query)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .chain_err(|| format!("Error requesting {}", path))
+    }
+
+    fn find_zone_id(&self) -> Result<String> {
+        let zones: Zones = self.get("zones", &[("name", &self.zone)])?
+            .json()
+            .chain_err(|| "Error parsing zones")?;
+
+        zones
+            .zones
+            .into_iter()
+            .find(|zone| zone.name.eq_ignore_ascii_case(&self.zone))
+            .map(|zone| zone.id)
+            .ok_or_else(|| format!("Zone {} not found", self.zone).into())
+    }
+
+    /// Searches the records of the zone page by page, large zones don't fit on one page.
+    fn find_record(&self, zone_id: &str, record_type: &str) -> Result<Option<Record>> {
+        let mut page = 1;
+        loop {
+            let records: Records = self.get(
+                "records",
+                &[
+                    ("zone_id", zone_id),
+                    ("page", &page.to_string()),
+                    ("per_page", RECORDS_PER_PAGE),
+                ],
+            )?
+                .json()
+                .chain_err(|| "Error parsing records")?;
+
+            let last_page = records.meta.as_ref().map_or(page, |meta| meta.pagination.last_page);
+            let found = records
+                .records
+                .into_iter()
+                .find(|record| record.name == self.record_name && record.record_type == record_type);
+
+            if found.is_some() || page >= last_page {
+                return Ok(found);
+            }
+            page += 1;
+        }
+    }
+}
+
+impl DnsService for HetznerDnsClient {
+    fn update(&self, addr: &IpAddr) -> Result<Update> {
+        info!(self.logger, "called with: {}", addr);
+
+        let record_type = match *addr {
+            IpAddr::V4(_) => "A",
+            IpAddr::V6(_) => "AAAA",
+        };
+
+        let zone_id = self.find_zone_id()?;
+        let record = self.find_record(&zone_id, record_type)?;
+
+        let update = RecordUpdate {
+            zone_id: &zone_id,
+            record_type,
+            name: &self.record_name,
+            value: addr.to_string(),
+            ttl: self.ttl,
+        };
+
+        let request = match record {
+            Some(ref record) if record.value == update.value => {
+                debug!(self.logger, "record {} already up to date", record.id);
+                return Ok(Update::Published);
+            }
+            Some(record) => {
+                debug!(self.logger, "updating record {}", record.id);
+                self.client
+                    .put(&format!("{}/records/{}", self.api_url, record.id))
+            }
+            None => {
+                debug!(self.logger, "creating {} record", record_type);
+                self.client.post(&format!("{}/records", self.api_url))
+            }
+        };
+
+        request
+            .header(API_TOKEN_HEADER, self.api_token.as_str())
+            .json(&update)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .chain_err(|| "Error updating record")?;
+
+        Ok(Update::Published)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::HetznerDnsConfig;
+    use slog;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug)]
+    struct Recorded {
+        method: String,
+        uri: String,
+        token: Option<String>,
+        body: String,
+    }
+
+    /// Serves `pages` of records, selected by the `page` query parameter.
+    fn mock_api(pages: &'static [&'static str]) -> (String, Arc<Mutex<Vec<Recorded>>>) {
+        use hyper::rt::{self, Future, Stream};
+        use hyper::service::service_fn;
+        use hyper::{Body, Request, Response, Server};
+        use std::thread;
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorder = requests.clone();
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let recorder = recorder.clone();
+            service_fn(move |req: Request<Body>| {
+                let recorder = recorder.clone();
+                let method = req.method().to_string();
+                let uri = req.uri().to_string();
+                let token = req.headers()
+                    .get(API_TOKEN_HEADER)
+                    .and_then(|token| token.to_str().ok())
+                    .map(String::from);
+
+                req.into_body().concat2().map(move |body| {
+                    let response = match uri.split('?').next() {
+                        Some("/zones") => {
+                            r#"{"zones": [{"id": "zone1", "name": "example.com"}]}"#
+                        }
+                        Some("/records") if method == "GET" => {
+                            let page = uri.split("page=").nth(1).and_then(|p| p.split('&').next());
+                            let page: usize = page.and_then(|p| p.parse().ok()).unwrap_or(1);
+                            pages[page - 1]
+                        }
+                        _ => r#"{}"#,
+                    };
+
+                    recorder.lock().unwrap().push(Recorded {
+                        method,
+                        uri,
+                        token,
+                        body: String::from_utf8_lossy(&body).into_owned(),
+                    });
+
+                    Response::new(Body::from(response))
+                })
+            })
+        });
+
+        let url = format!("http://{}", server.local_addr());
+        thread::spawn(move || rt::run(server.map_err(|e| panic!("{}", e))));
+
+        (url, requests)
+    }
+
+    fn client(url: String) -> HetznerDnsClient {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let config = HetznerDnsConfig {
+            api_token: "token".to_owned(),
+            api_url: url,
+            zone: Some("example.com".to_owned()),
+            ttl: None,
+        };
+
+        HetznerDnsClient::new(&logger, "home.example.com", &config).unwrap()
+    }
+
+    #[test]
+    fn relative_names() {
+        assert_eq!(relative_name("example.com", "example.com").unwrap(), "@");
+        assert_eq!(relative_name("a.b.example.com.", "example.com").unwrap(), "a.b");
+        assert!(relative_name("badexample.com", "example.com").is_err());
+        assert!(relative_name("example.org", "example.com").is_err());
+        assert_eq!(relative_name("Home.Example.com", "example.COM.").unwrap(), "home");
+        assert_eq!(relative_name("example.com", "Example.com").unwrap(), "@");
+    }
+
+    #[test]
+    fn zone_is_lower_case() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let config = HetznerDnsConfig {
+            api_token: "token".to_owned(),
+            api_url: "http://127.0.0.1:1".to_owned(),
+            zone: Some("Example.com.".to_owned()),
+            ttl: None,
+        };
+
+        let client = HetznerDnsClient::new(&logger, "home.example.com", &config).unwrap();
+        assert_eq!(client.zone, "example.com");
+        assert_eq!(client.record_name, "home");
+    }
+
+    #[test]
+    fn updates_existing_record() {
+        let (url, requests) = mock_api(&[r#"{"records": [
+                {"id": "rec1", "type": "AAAA", "name": "home", "value": "::1"},
+                {"id": "rec2", "type": "A", "name": "home", "value": "192.0.2.1"}
+            ]}"#]);
+
+        client(url).update(&"192.0.2.2".parse().unwrap()).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.token == Some("token".to_owned())));
+        assert_eq!(requests[0].uri, "/zones?name=example.com");
+        assert_eq!(requests[1].uri, "/records?zone_id=zone1&page=1&per_page=100");
+        assert_eq!(requests[2].method, "PUT");
+        assert_eq!(requests[2].uri, "/records/rec2");
+        assert!(requests[2].body.contains(r#""value":"192.0.2.2""#));
+    }
+
+    #[test]
+    fn creates_missing_record() {
+        let (url, requests) = mock_api(&[r#"{"records": []}"#]);
+
+        client(url).update(&"2001:db8::1".parse().unwrap()).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].method, "POST");
+        assert_eq!(requests[2].uri, "/records");
+        assert!(requests[2].body.contains(r#""type":"AAAA""#));
+        assert!(requests[2].body.contains(r#""name":"home""#));
+    }
+
+    #[test]
+    fn skips_unchanged_record() {
+        let (url, requests) = mock_api(&[
+            r#"{"records": [{"id": "rec1", "type": "A", "name": "home", "value": "192.0.2.1"}]}"#,
+        ]);
+
+        client(url).update(&"192.0.2.1".parse().unwrap()).unwrap();
+
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn searches_all_pages() {
+        let (url, requests) = mock_api(&[
+            r#"{"records": [{"id": "rec1", "type": "A", "name": "www", "value": "192.0.2.1"}],
+                "meta": {"pagination": {"page": 1, "per_page": 1, "last_page": 2, "total_entries": 2}}}"#,
+            r#"{"records": [{"id": "rec2", "type": "A", "name": "home", "value": "192.0.2.1"}],
+                "meta": {"pagination": {"page": 2, "per_page": 1, "last_page": 2, "total_entries": 2}}}"#,
+        ]);
+
+        client(url).update(&"192.0.2.2".parse().unwrap()).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[2].uri, "/records?zone_id=zone1&page=2&per_page=100");
+        assert_eq!(requests[3].method, "PUT");
+        assert_eq!(requests[3].uri, "/records/rec2");
+    }
+}
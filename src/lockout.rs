@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    /// Time of the first failure in the current window
+    since: i64,
+    locked_until: Option<i64>,
+}
+
+/// Counts failed logins per client address and locks out clients with too many of them.
+pub struct Lockout {
+    max_failures: u32,
+    window: i64,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl Lockout {
+    /// Locks out a client for `window` seconds after `max_failures` failures within
+    /// `window` seconds. `max_failures` 0 disables the lockout.
+    pub fn new(max_failures: u32, window: u64) -> Self {
+        Lockout {
+            max_failures,
+            window: window as i64,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the end of the lockout if `addr` is locked out at `now`.
+    pub fn locked_until(&self, addr: &IpAddr, now: i64) -> Option<i64> {
+        let failures = self.failures.lock().ok()?;
+        failures
+            .get(addr)
+            .and_then(|f| f.locked_until)
+            .filter(|&until| until > now)
+    }
+
+    /// Records a failed login and returns the end of the lockout if it locks `addr` out.
+    pub fn failure(&self, addr: &IpAddr, now: i64) -> Option<i64> {
+        if self.max_failures == 0 {
+            return None;
+        }
+
+        let mut failures = self.failures.lock().ok()?;
+
+        // forget clients which stopped trying, so the map doesn't grow forever
+        let window = self.window;
+        failures.retain(|_, f| f.since + window > now || f.locked_until.map_or(false, |u| u > now));
+
+        let entry = failures.entry(*addr).or_insert(Failures {
+            count: 0,
+            since: now,
+            locked_until: None,
+        });
+        entry.count += 1;
+
+        if entry.count >= self.max_failures && entry.locked_until.is_none() {
+            let until = now + self.window;
+            entry.locked_until = Some(until);
+            Some(until)
+        } else {
+            None
+        }
+    }
+
+    /// Forgets the failures of `addr` after a successful login.
+    pub fn success(&self, addr: &IpAddr) {
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.remove(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_out_after_max_failures() {
+        let lockout = Lockout::new(3, 60);
+        let addr: IpAddr = "198.51.100.1".parse().unwrap();
+        let other: IpAddr = "198.51.100.2".parse().unwrap();
+
+        assert_eq!(lockout.failure(&addr, 100), None);
+        assert_eq!(lockout.failure(&addr, 110), None);
+        assert_eq!(lockout.locked_until(&addr, 110), None);
+        assert_eq!(lockout.failure(&addr, 120), Some(180));
+
+        assert_eq!(lockout.locked_until(&addr, 179), Some(180));
+        assert_eq!(lockout.locked_until(&other, 179), None);
+        assert_eq!(lockout.locked_until(&addr, 180), None);
+    }
+
+    #[test]
+    fn failures_expire() {
+        let lockout = Lockout::new(2, 60);
+        let addr: IpAddr = "198.51.100.1".parse().unwrap();
+
+        assert_eq!(lockout.failure(&addr, 100), None);
+        assert_eq!(lockout.failure(&addr, 160), None);
+        lockout.success(&addr);
+        assert_eq!(lockout.failure(&addr, 170), None);
+        assert_eq!(lockout.failure(&addr, 171), Some(231));
+    }
+
+    #[test]
+    fn disabled() {
+        let lockout = Lockout::new(0, 60);
+        let addr: IpAddr = "198.51.100.1".parse().unwrap();
+
+        for now in 0..100 {
+            assert_eq!(lockout.failure(&addr, now), None);
+        }
+        assert_eq!(lockout.locked_until(&addr, 100), None);
+    }
+}
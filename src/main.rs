@@ -25,6 +25,24 @@ extern crate dotenv;
 extern crate itertools;
 extern crate consistenttime;
 extern crate toml;
+extern crate reqwest;
+extern crate hmac;
+extern crate sha2;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate native_tls;
+extern crate openssl;
+extern crate tokio;
+extern crate tokio_signal;
+extern crate tokio_timer;
+extern crate tokio_io;
+extern crate tokio_tls;
+extern crate bcrypt;
+extern crate argon2;
+extern crate sha1;
+extern crate ipnet;
+extern crate libc;
+extern crate serde_json;
 
 #[cfg(test)]
 #[macro_use]
@@ -32,20 +50,74 @@ extern crate quickcheck;
 
 mod config;
 mod dns;
+mod dyndns2;
 mod envvars;
 mod errors;
+mod hetzner_dns;
+mod lockout;
+mod metrics;
+mod native_signer;
 mod openpgp;
+mod outbox;
+mod password;
+mod policy;
+mod proxy;
+mod rfc2136;
 mod server;
+mod state;
+mod status;
+mod systemd;
 mod template;
+mod tls;
 
 use errors::*;
 
+/// Checks the gpg setup with a test signature, so a broken or expiring key is noticed
+/// at startup and not with the first update.
+fn preflight(
+    logger: &slog::Logger,
+    builder: &openpgp::GpgSignedMessageBuilder,
+    expiry_warning_days: u64,
+) -> Result<()> {
+    use chrono::{TimeZone, Utc};
+
+    let now = Utc::now().timestamp();
+    let key = builder
+        .preflight(now)
+        .chain_err(|| "Signing key preflight failed")?;
+
+    match key.expires {
+        Some(expires) => {
+            let expires_at = Utc.timestamp(expires, 0).to_rfc3339();
+            if expires - now < expiry_warning_days as i64 * 86_400 {
+                warn!(logger, "signing key expires soon";
+                      "fingerprint" => &key.fingerprint, "expires" => expires_at,
+                      "days" => (expires - now) / 86_400);
+            } else {
+                info!(logger, "checked signing key";
+                      "fingerprint" => &key.fingerprint, "expires" => expires_at);
+            }
+        }
+        None => info!(logger, "checked signing key"; "fingerprint" => &key.fingerprint),
+    }
+
+    Ok(())
+}
+
 fn run(args: Args, root_logger: &slog::Logger) -> Result<()> {
-    use config::Config;
-    use dns::HetznerClient;
-    use openpgp::Sha1SignedMessageBuilder;
+    use config::{Config, DnsServiceConfig, PgpSignerConfig};
+    use dns::{spawn_retry_worker, DnsService, HetznerClient};
+    use hetzner_dns::HetznerDnsClient;
+    use metrics::Metrics;
+    use native_signer::NativeSignedMessageBuilder;
+    use openpgp::{GpgSignedMessageBuilder, SignedMessageBuilder};
+    use outbox::Outbox;
+    use rfc2136::Rfc2136Client;
     use server::run_server;
+    use state::StateStore;
+    use status::LastErrors;
 
+    use std::collections::BTreeMap;
     use std::sync::Arc;
 
     envvars::use_dotenv()?;
@@ -53,11 +125,99 @@ fn run(args: Args, root_logger: &slog::Logger) -> Result<()> {
     let config = Config::from_source(&args.config)?;
     debug!(root_logger, "config: {:#?}", config);
 
-    let signed_message_builder = Sha1SignedMessageBuilder::new(&config);
+    for user in config.users.iter().filter(|user| user.password.is_deprecated()) {
+        warn!(root_logger, "unsalted SHA-1 password hashes are deprecated, use bcrypt or argon2";
+            "user" => user.name.clone());
+    }
+
+    let state = StateStore::load(config.state_file.as_ref()).chain_err(|| "Error loading state")?;
+    let state = Arc::new(state);
+
+    let metrics = Arc::new(Metrics::default());
+    let errors = Arc::new(LastErrors::default());
+    let mut services: BTreeMap<String, Box<dyn DnsService + Send + Sync>> = BTreeMap::new();
+    let mut retry = None;
 
-    let dns_service = HetznerClient::new(root_logger, &config, signed_message_builder);
+    match config.dns_service {
+        DnsServiceConfig::HetznerRobot(ref robot) => {
+            let outbox = Outbox::load(
+                robot.outbox_file.as_ref(),
+                robot.retry_initial_delay,
+                robot.retry_max_delay,
+            ).chain_err(|| "Error loading outbox")?;
+
+            let pending = outbox.pending_count()?;
+            if pending > 0 {
+                info!(root_logger, "outbox contains pending updates"; "pending" => pending);
+            }
+
+            let outbox = Arc::new(outbox);
+            let mut clients = Vec::new();
+
+            let signed_message_builder: Arc<dyn SignedMessageBuilder> = match robot.signer {
+                PgpSignerConfig::Gpg(ref gpg) => {
+                    let builder = GpgSignedMessageBuilder::new(gpg);
+                    preflight(root_logger, &builder, gpg.expiry_warning_days)?;
+                    Arc::new(builder)
+                }
+                PgpSignerConfig::Native {
+                    ref key_file,
+                    ref passphrase_file,
+                } => {
+                    let builder = NativeSignedMessageBuilder::load(
+                        key_file,
+                        passphrase_file.as_ref().map(String::as_str),
+                    )?;
+                    info!(root_logger, "loaded signing key"; "fingerprint" => builder.fingerprint());
+                    Arc::new(builder)
+                }
+            };
+
+            for domain in &config.domains {
+                let client = Arc::new(
+                    HetznerClient::new(
+                        root_logger,
+                        domain,
+                        robot,
+                        signed_message_builder.clone(),
+                        state.clone(),
+                        outbox.clone(),
+                        metrics.clone(),
+                        errors.clone(),
+                    ).chain_err(|| "Error creating Hetzner robot client")?,
+                );
+                clients.push(client.clone());
+                services.insert(domain.name.clone(), Box::new(client));
+            }
+
+            retry = Some((outbox, spawn_retry_worker(clients)?));
+        }
+        DnsServiceConfig::HetznerDns(ref hetzner_dns) => for domain in &config.domains {
+            let client = HetznerDnsClient::new(root_logger, &domain.name, hetzner_dns)
+                .chain_err(|| "Error creating Hetzner DNS client")?;
+            services.insert(domain.name.clone(), Box::new(client));
+        },
+        DnsServiceConfig::Rfc2136(ref rfc2136) => for domain in &config.domains {
+            let client = Rfc2136Client::new(root_logger, &domain.name, rfc2136)
+                .chain_err(|| "Error creating RFC 2136 client")?;
+            services.insert(domain.name.clone(), Box::new(client));
+        },
+    }
+
+    let config = Arc::new(config);
+    let deadline = run_server(root_logger, services, config, state.clone(), metrics, errors)
+        .chain_err(|| "Error running server")?;
+
+    if let Some((outbox, worker)) = retry {
+        if !worker.stop(deadline) {
+            warn!(root_logger, "retry worker didn't stop in time");
+        }
+        outbox.flush().chain_err(|| "Error writing outbox")?;
+    }
+    state.flush().chain_err(|| "Error writing state")?;
 
-    run_server(root_logger, dns_service, Arc::new(config)).chain_err(|| "Error running server")
+    info!(root_logger, "Application stopped");
+    Ok(())
 }
 
 fn main() {
@@ -0,0 +1,198 @@
+use dyndns2::ReturnCode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the duration buckets in seconds, gpg and SMTP take up to several seconds.
+static BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        for (bound, count) in BUCKETS.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Values {
+    requests: BTreeMap<&'static str, u64>,
+    auth_failures: u64,
+    gpg_sign: Histogram,
+    smtp_send: Histogram,
+    updates: BTreeMap<(String, &'static str), u64>,
+}
+
+/// Counters of the daemon, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    values: Mutex<Values>,
+}
+
+impl Metrics {
+    fn with<F: FnOnce(&mut Values)>(&self, f: F) {
+        if let Ok(mut values) = self.values.lock() {
+            f(&mut values);
+        }
+    }
+
+    /// Counts the return codes sent to a client.
+    pub fn requests(&self, codes: &[ReturnCode]) {
+        self.with(|values| {
+            for code in codes {
+                *values.requests.entry(code.name()).or_insert(0) += 1;
+            }
+        })
+    }
+
+    pub fn auth_failure(&self) {
+        self.with(|values| values.auth_failures += 1)
+    }
+
+    /// Counts the outcome of an update of `domain`.
+    pub fn update(&self, domain: &str, code: &ReturnCode) {
+        self.with(|values| {
+            *values
+                .updates
+                .entry((domain.to_owned(), code.name()))
+                .or_insert(0) += 1;
+        })
+    }
+
+    pub fn gpg_sign(&self, duration: Duration) {
+        self.with(|values| values.gpg_sign.observe(seconds(duration)))
+    }
+
+    pub fn smtp_send(&self, duration: Duration) {
+        self.with(|values| values.smtp_send.observe(seconds(duration)))
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        self.with(|values| {
+            header(&mut out, "dyndns_requests_total", "counter", "Return codes sent to clients.");
+            for (code, count) in &values.requests {
+                let _ = writeln!(out, "dyndns_requests_total{{code=\"{}\"}} {}", code, count);
+            }
+
+            header(&mut out, "dyndns_auth_failures_total", "counter", "Requests with wrong or missing credentials.");
+            let _ = writeln!(out, "dyndns_auth_failures_total {}", values.auth_failures);
+
+            header(&mut out, "dyndns_gpg_sign_duration_seconds", "histogram", "Time spent signing update mails.");
+            values.gpg_sign.render(&mut out, "dyndns_gpg_sign_duration_seconds");
+
+            header(&mut out, "dyndns_smtp_send_duration_seconds", "histogram", "Time spent sending update mails.");
+            values.smtp_send.render(&mut out, "dyndns_smtp_send_duration_seconds");
+
+            header(&mut out, "dyndns_updates_total", "counter", "Updates by domain and return code.");
+            for (&(ref domain, code), count) in &values.updates {
+                let _ = writeln!(
+                    out,
+                    "dyndns_updates_total{{domain=\"{}\",code=\"{}\"}} {}",
+                    escape(domain),
+                    code,
+                    count
+                );
+            }
+        });
+
+        out
+    }
+}
+
+/// Runs `f` and reports its duration to `observe`.
+pub fn timed<T, F, O>(observe: O, f: F) -> T
+where
+    F: FnOnce() -> T,
+    O: FnOnce(Duration),
+{
+    let start = Instant::now();
+    let result = f();
+    observe(start.elapsed());
+    result
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value, domains are validated but configured by the user.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        let ip = "93.184.216.34".parse().unwrap();
+
+        metrics.requests(&[ReturnCode::Good(ip), ReturnCode::NoChg(ip), ReturnCode::Good(ip)]);
+        metrics.auth_failure();
+        metrics.update("example.com", &ReturnCode::Good(ip));
+        metrics.gpg_sign(Duration::from_millis(200));
+        metrics.gpg_sign(Duration::from_secs(3));
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+
+        for expected in &[
+            "dyndns_requests_total{code=\"good\"} 2",
+            "dyndns_requests_total{code=\"nochg\"} 1",
+            "dyndns_auth_failures_total 1",
+            "dyndns_gpg_sign_duration_seconds_bucket{le=\"0.1\"} 0",
+            "dyndns_gpg_sign_duration_seconds_bucket{le=\"0.25\"} 1",
+            "dyndns_gpg_sign_duration_seconds_bucket{le=\"5\"} 2",
+            "dyndns_gpg_sign_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "dyndns_gpg_sign_duration_seconds_sum 3.2",
+            "dyndns_gpg_sign_duration_seconds_count 2",
+            "dyndns_smtp_send_duration_seconds_count 0",
+            "dyndns_updates_total{domain=\"example.com\",code=\"good\"} 1",
+            "# TYPE dyndns_smtp_send_duration_seconds histogram",
+        ] {
+            assert!(lines.contains(expected), "{} missing in\n{}", expected, text);
+        }
+    }
+}
@@ -0,0 +1,875 @@
+use errors::*;
+use openpgp::SignedMessageBuilder;
+use openssl::pkey::{PKey, Private};
+
+const TAG_SIGNATURE: u8 = 2;
+const TAG_SECRET_KEY: u8 = 5;
+const TAG_SECRET_SUBKEY: u8 = 7;
+
+const ALGORITHM_RSA: u8 = 1;
+const ALGORITHM_RSA_SIGN_ONLY: u8 = 3;
+const ALGORITHM_EDDSA: u8 = 22;
+
+const HASH_SHA256: u8 = 8;
+
+const SIGNATURE_CANONICAL_TEXT: u8 = 0x01;
+const SIGNATURE_SUBKEY_BINDING: u8 = 0x18;
+const SIGNATURE_SUBKEY_REVOCATION: u8 = 0x28;
+
+const SUBPACKET_CREATION_TIME: u8 = 2;
+const SUBPACKET_KEY_EXPIRATION_TIME: u8 = 9;
+const SUBPACKET_ISSUER: u8 = 16;
+const SUBPACKET_KEY_FLAGS: u8 = 27;
+const SUBPACKET_ISSUER_FINGERPRINT: u8 = 33;
+
+const KEY_FLAG_SIGN: u8 = 0x02;
+
+const S2K_USAGE_NONE: u8 = 0;
+const S2K_USAGE_SHA1: u8 = 254;
+const S2K_USAGE_CHECKSUM: u8 = 255;
+
+const S2K_SIMPLE: u8 = 0;
+const S2K_SALTED: u8 = 1;
+const S2K_ITERATED_SALTED: u8 = 3;
+
+const CIPHER_AES128: u8 = 7;
+const CIPHER_AES192: u8 = 8;
+const CIPHER_AES256: u8 = 9;
+
+/// 1.3.6.1.4.1.11591.15.1
+static ED25519_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01];
+
+/// Creates cleartext signatures in-process with a secret key read from an armored
+/// key file, so neither the gpg binary nor a keyring is needed.
+pub struct NativeSignedMessageBuilder {
+    key: SecretKey,
+}
+
+impl NativeSignedMessageBuilder {
+    /// Loads the signing key: the newest subkey which may sign and is neither revoked
+    /// nor expired, otherwise the primary key. A protected key is unlocked with the
+    /// first line of `passphrase_file`, like gpg does.
+    pub fn load(path: &str, passphrase_file: Option<&str>) -> Result<Self> {
+        use chrono::Utc;
+
+        let armored = read_file(path)?;
+        let passphrase = match passphrase_file {
+            Some(passphrase_file) => Some(read_file(passphrase_file)?),
+            None => None,
+        };
+        let passphrase = passphrase
+            .as_ref()
+            .map(|passphrase| passphrase.lines().next().unwrap_or("").as_bytes());
+
+        let data = dearmor(&armored, "PGP PRIVATE KEY BLOCK")?;
+        let body = signing_key(&packets(&data)?, Utc::now().timestamp())?;
+        let key = SecretKey::parse(body, passphrase)
+            .chain_err(|| format!("Error loading secret key from {}", path))?;
+
+        Ok(NativeSignedMessageBuilder { key })
+    }
+
+    /// Fingerprint of the signing key in hex.
+    pub fn fingerprint(&self) -> String {
+        self.key
+            .fingerprint
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect()
+    }
+}
+
+fn read_file(path: &str) -> Result<String> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .chain_err(|| format!("Error reading {}", path))?;
+    Ok(content)
+}
+
+impl SignedMessageBuilder for NativeSignedMessageBuilder {
+    fn sign(&self, text: &str) -> Result<String> {
+        use chrono::Utc;
+
+        let (escaped, canonical) = cleartext(text);
+        let signature = self.key.sign(&canonical, Utc::now().timestamp() as u32)?;
+
+        let mut message = String::new();
+        message.push_str("-----BEGIN PGP SIGNED MESSAGE-----\n");
+        message.push_str("Hash: SHA256\n\n");
+        message.push_str(&escaped);
+        message.push('\n');
+        message.push_str(&armor("PGP SIGNATURE", &signature));
+        Ok(message)
+    }
+}
+
+/// Returns the dash-escaped text of a cleartext signed message and the canonical
+/// text which is signed (RFC 4880, section 7.1).
+fn cleartext(text: &str) -> (String, Vec<u8>) {
+    // the line ending before the signature belongs to the framework
+    let text = if text.ends_with('\n') {
+        &text[..text.len() - 1]
+    } else {
+        text
+    };
+
+    let mut escaped = Vec::new();
+    let mut canonical = Vec::new();
+    for line in text.split('\n') {
+        let line = line.trim_end_matches('\r');
+
+        if line.starts_with('-') {
+            escaped.push(format!("- {}", line));
+        } else {
+            escaped.push(line.to_owned());
+        }
+        canonical.push(line.trim_end_matches(|c| c == ' ' || c == '\t'));
+    }
+
+    (escaped.join("\n"), canonical.join("\r\n").into_bytes())
+}
+
+struct SecretKey {
+    algorithm: u8,
+    fingerprint: Vec<u8>,
+    key: PKey<Private>,
+}
+
+impl SecretKey {
+    /// Parses the body of a version 4 secret key or subkey packet, a protected key is
+    /// decrypted with `passphrase`.
+    fn parse(body: &[u8], passphrase: Option<&[u8]>) -> Result<Self> {
+        use sha1::{Digest, Sha1};
+
+        let mut reader = Reader::new(body);
+        let version = reader.u8()?;
+        if version != 4 {
+            bail!("Unsupported key version {}", version);
+        }
+        reader.take(4)?; // creation time
+        let algorithm = reader.u8()?;
+
+        let public = match algorithm {
+            ALGORITHM_RSA | ALGORITHM_RSA_SIGN_ONLY => {
+                let n = reader.mpi()?;
+                let e = reader.mpi()?;
+                PublicKey::Rsa { n, e }
+            }
+            ALGORITHM_EDDSA => {
+                let oid_len = reader.u8()? as usize;
+                if reader.take(oid_len)? != ED25519_OID {
+                    bail!("Unsupported EdDSA curve");
+                }
+                reader.mpi()?; // public point
+                PublicKey::Ed25519
+            }
+            algorithm => bail!("Unsupported key algorithm {}", algorithm),
+        };
+
+        let public_len = body.len() - reader.remaining();
+        let mut fingerprint_data = vec![0x99, (public_len >> 8) as u8, public_len as u8];
+        fingerprint_data.extend_from_slice(&body[..public_len]);
+        let fingerprint = Sha1::digest(&fingerprint_data).to_vec();
+
+        let secret = match reader.u8()? {
+            S2K_USAGE_NONE => {
+                let secret = reader.take(reader.remaining())?.to_vec();
+                if !valid_checksum(&secret) {
+                    bail!("Invalid secret key checksum");
+                }
+                secret
+            }
+            usage @ S2K_USAGE_SHA1 | usage @ S2K_USAGE_CHECKSUM => {
+                let passphrase = passphrase.ok_or("Secret key is protected by a passphrase, none configured")?;
+                let secret = decrypt(&mut reader, passphrase)?;
+                let valid = if usage == S2K_USAGE_SHA1 {
+                    secret.len() >= 20 && Sha1::digest(&secret[..secret.len() - 20])[..] == secret[secret.len() - 20..]
+                } else {
+                    valid_checksum(&secret)
+                };
+                if !valid {
+                    bail!("Wrong passphrase or corrupt secret key");
+                }
+                secret
+            }
+            usage => bail!("Unsupported secret key protection {}", usage),
+        };
+
+        let mut reader = Reader::new(&secret);
+        let key = match public {
+            PublicKey::Rsa { n, e } => {
+                let d = reader.mpi()?;
+                let p = reader.mpi()?;
+                let q = reader.mpi()?;
+                reader.mpi()?; // u, OpenSSL needs the inverse of q instead
+                rsa_key(n, e, d, p, q)?
+            }
+            PublicKey::Ed25519 => {
+                let secret = reader.mpi()?;
+                if secret.len() > 32 {
+                    bail!("Invalid Ed25519 secret key");
+                }
+                let mut seed = [0; 32];
+                seed[32 - secret.len()..].copy_from_slice(secret);
+                PKey::private_key_from_raw_bytes(&seed, ::openssl::pkey::Id::ED25519)
+                    .chain_err(|| "Invalid Ed25519 secret key")?
+            }
+        };
+
+        Ok(SecretKey {
+            algorithm,
+            fingerprint,
+            key,
+        })
+    }
+
+    /// Creates a signature packet over `text` (RFC 4880, section 5.2.3).
+    fn sign(&self, text: &[u8], created: u32) -> Result<Vec<u8>> {
+        use openssl::hash::MessageDigest;
+        use openssl::sign::Signer;
+        use sha2::{Digest, Sha256};
+
+        let mut hashed_subpackets = Vec::new();
+        subpacket(&mut hashed_subpackets, SUBPACKET_CREATION_TIME, &be32(created));
+        let mut issuer_fingerprint = vec![4];
+        issuer_fingerprint.extend_from_slice(&self.fingerprint);
+        subpacket(&mut hashed_subpackets, SUBPACKET_ISSUER_FINGERPRINT, &issuer_fingerprint);
+
+        let mut unhashed_subpackets = Vec::new();
+        subpacket(&mut unhashed_subpackets, SUBPACKET_ISSUER, &self.fingerprint[12..]);
+
+        let mut hashed = vec![4, SIGNATURE_CANONICAL_TEXT, self.algorithm, HASH_SHA256];
+        hashed.extend_from_slice(&be16(hashed_subpackets.len()));
+        hashed.extend_from_slice(&hashed_subpackets);
+
+        let mut data = text.to_vec();
+        data.extend_from_slice(&hashed);
+        data.extend_from_slice(&[4, 0xff]);
+        data.extend_from_slice(&be32(hashed.len() as u32));
+        let digest = Sha256::digest(&data);
+
+        let mpis = if self.algorithm == ALGORITHM_EDDSA {
+            let mut signer = Signer::new_without_digest(&self.key)
+                .chain_err(|| "Error creating signer")?;
+            let signature = signer
+                .sign_oneshot_to_vec(&digest)
+                .chain_err(|| "Error signing")?;
+            let (r, s) = signature.split_at(32);
+            [mpi(r), mpi(s)].concat()
+        } else {
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.key)
+                .chain_err(|| "Error creating signer")?;
+            signer.update(&data).chain_err(|| "Error signing")?;
+            mpi(&signer.sign_to_vec().chain_err(|| "Error signing")?)
+        };
+
+        let mut body = hashed;
+        body.extend_from_slice(&be16(unhashed_subpackets.len()));
+        body.extend_from_slice(&unhashed_subpackets);
+        body.extend_from_slice(&digest[..2]);
+        body.extend_from_slice(&mpis);
+
+        let mut packet = vec![0xc0 | TAG_SIGNATURE];
+        packet.extend_from_slice(&packet_length(body.len()));
+        packet.extend_from_slice(&body);
+        Ok(packet)
+    }
+}
+
+/// Checks the two octet sum at the end of the secret key material.
+fn valid_checksum(secret: &[u8]) -> bool {
+    if secret.len() < 2 {
+        return false;
+    }
+    let (data, checksum) = secret.split_at(secret.len() - 2);
+    let sum = data.iter().fold(0u16, |sum, &b| sum.wrapping_add(u16::from(b)));
+    checksum == be16(sum as usize)
+}
+
+/// Decrypts the secret key material following the S2K usage octet (RFC 4880, section 5.5.3).
+fn decrypt(reader: &mut Reader, passphrase: &[u8]) -> Result<Vec<u8>> {
+    use openssl::symm::{self, Cipher};
+
+    let cipher = match reader.u8()? {
+        CIPHER_AES128 => Cipher::aes_128_cfb128(),
+        CIPHER_AES192 => Cipher::aes_192_cfb128(),
+        CIPHER_AES256 => Cipher::aes_256_cfb128(),
+        cipher => bail!("Unsupported secret key cipher {}", cipher),
+    };
+    let key = s2k(reader, passphrase, cipher.key_len())?;
+    let iv = reader.take(16)?;
+    let encrypted = reader.take(reader.remaining())?;
+
+    symm::decrypt(cipher, &key, Some(iv), encrypted).chain_err(|| "Error decrypting secret key")
+}
+
+/// Reads a string-to-key specifier and derives a key of `len` octets from `passphrase`
+/// (RFC 4880, section 3.7).
+fn s2k(reader: &mut Reader, passphrase: &[u8], len: usize) -> Result<Vec<u8>> {
+    use openssl::hash::{Hasher, MessageDigest};
+
+    let specifier = reader.u8()?;
+    let digest = match reader.u8()? {
+        2 => MessageDigest::sha1(),
+        8 => MessageDigest::sha256(),
+        9 => MessageDigest::sha384(),
+        10 => MessageDigest::sha512(),
+        11 => MessageDigest::sha224(),
+        hash => bail!("Unsupported S2K hash {}", hash),
+    };
+
+    let mut data = Vec::new();
+    let count = match specifier {
+        S2K_SIMPLE => 0,
+        S2K_SALTED => {
+            data.extend_from_slice(reader.take(8)?);
+            0
+        }
+        S2K_ITERATED_SALTED => {
+            data.extend_from_slice(reader.take(8)?);
+            let c = usize::from(reader.u8()?);
+            (16 + (c & 15)) << ((c >> 4) + 6)
+        }
+        specifier => bail!("Unsupported S2K specifier {}", specifier),
+    };
+    data.extend_from_slice(passphrase);
+    // the salt and passphrase are hashed at least once, even if they exceed the count
+    let count = count.max(data.len());
+
+    // hash larger chunks instead of the short salt and passphrase, counts go up to 65 MB
+    let mut chunk = Vec::new();
+    while chunk.len() < 8192 && !data.is_empty() {
+        chunk.extend_from_slice(&data);
+    }
+
+    let mut key = Vec::new();
+    let mut preload = 0;
+    while key.len() < len {
+        let mut hasher = Hasher::new(digest).chain_err(|| "Error creating S2K hash")?;
+        // each further hash context is preloaded with one more zero octet
+        hasher.update(&vec![0; preload]).chain_err(|| "Error hashing")?;
+
+        let mut remaining = count;
+        while remaining > 0 {
+            let n = remaining.min(chunk.len());
+            hasher.update(&chunk[..n]).chain_err(|| "Error hashing")?;
+            remaining -= n;
+        }
+
+        key.extend_from_slice(&hasher.finish().chain_err(|| "Error hashing")?);
+        preload += 1;
+    }
+    key.truncate(len);
+
+    Ok(key)
+}
+
+enum PublicKey<'a> {
+    Rsa { n: &'a [u8], e: &'a [u8] },
+    Ed25519,
+}
+
+fn rsa_key(n: &[u8], e: &[u8], d: &[u8], p: &[u8], q: &[u8]) -> Result<PKey<Private>> {
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::rsa::Rsa;
+
+    let key = (|| {
+        let bn = BigNum::from_slice;
+        let (n, e, d, p, q) = (bn(n)?, bn(e)?, bn(d)?, bn(p)?, bn(q)?);
+        let one = BigNum::from_u32(1)?;
+        let mut ctx = BigNumContext::new()?;
+
+        let mut p1 = BigNum::new()?;
+        p1.checked_sub(&p, &one)?;
+        let mut q1 = BigNum::new()?;
+        q1.checked_sub(&q, &one)?;
+
+        let mut dmp1 = BigNum::new()?;
+        dmp1.nnmod(&d, &p1, &mut ctx)?;
+        let mut dmq1 = BigNum::new()?;
+        dmq1.nnmod(&d, &q1, &mut ctx)?;
+        let mut iqmp = BigNum::new()?;
+        iqmp.mod_inverse(&q, &p, &mut ctx)?;
+
+        let rsa = Rsa::from_private_components(n, e, d, p, q, dmp1, dmq1, iqmp)?;
+        PKey::from_rsa(rsa)
+    })();
+
+    key.chain_err(|| "Invalid RSA secret key")
+}
+
+/// Returns the body of the key to sign with at `now`.
+fn signing_key<'a>(packets: &[Packet<'a>], now: i64) -> Result<&'a [u8]> {
+    let primary = packets
+        .iter()
+        .find(|p| p.tag == TAG_SECRET_KEY)
+        .ok_or_else(|| "No secret key found")?;
+
+    let mut subkeys: Vec<Subkey> = Vec::new();
+    for packet in packets {
+        match packet.tag {
+            TAG_SECRET_SUBKEY => subkeys.push(Subkey::new(packet.body)?),
+            TAG_SIGNATURE => if let Some(subkey) = subkeys.last_mut() {
+                if let Some(subpackets) = hashed_subpackets(packet.body, SIGNATURE_SUBKEY_BINDING) {
+                    let binding = Binding::new(&subpackets);
+                    // a newer binding signature supersedes the older ones
+                    if subkey.binding.as_ref().map_or(true, |b| binding.signed >= b.signed) {
+                        subkey.binding = Some(binding);
+                    }
+                } else if hashed_subpackets(packet.body, SIGNATURE_SUBKEY_REVOCATION).is_some() {
+                    subkey.revoked = true;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    Ok(subkeys
+        .iter()
+        .filter(|subkey| subkey.may_sign(now))
+        .max_by_key(|subkey| subkey.created)
+        .map_or(primary.body, |subkey| subkey.body))
+}
+
+struct Subkey<'a> {
+    body: &'a [u8],
+    created: u32,
+    binding: Option<Binding>,
+    revoked: bool,
+}
+
+impl<'a> Subkey<'a> {
+    fn new(body: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader::new(body);
+        reader.u8()?; // version
+        Ok(Subkey {
+            body,
+            created: reader.u32()?,
+            binding: None,
+            revoked: false,
+        })
+    }
+
+    fn may_sign(&self, now: i64) -> bool {
+        let binding = match self.binding {
+            Some(ref binding) if !self.revoked => binding,
+            _ => return false,
+        };
+
+        // the expiration time counts from the creation of the key, 0 means never
+        let expired = binding
+            .expiration
+            .map_or(false, |expiration| expiration != 0 && i64::from(self.created) + i64::from(expiration) <= now);
+        binding.flags & KEY_FLAG_SIGN != 0 && !expired
+    }
+}
+
+/// The parts of a subkey binding signature which decide whether the subkey may sign.
+struct Binding {
+    signed: u32,
+    flags: u8,
+    expiration: Option<u32>,
+}
+
+impl Binding {
+    fn new(subpackets: &[(u8, &[u8])]) -> Self {
+        let mut binding = Binding {
+            signed: 0,
+            flags: 0,
+            expiration: None,
+        };
+        for &(kind, data) in subpackets {
+            match kind {
+                SUBPACKET_CREATION_TIME => binding.signed = Reader::new(data).u32().unwrap_or(0),
+                SUBPACKET_KEY_FLAGS if !data.is_empty() => binding.flags = data[0],
+                SUBPACKET_KEY_EXPIRATION_TIME => binding.expiration = Reader::new(data).u32().ok(),
+                _ => {}
+            }
+        }
+        binding
+    }
+}
+
+/// Returns type and data of the hashed subpackets of a version 4 signature of type `signature_type`.
+fn hashed_subpackets(body: &[u8], signature_type: u8) -> Option<Vec<(u8, &[u8])>> {
+    let mut reader = Reader::new(body);
+    if reader.u8().ok()? != 4 || reader.u8().ok()? != signature_type {
+        return None;
+    }
+    reader.take(2).ok()?;
+    let len = reader.u16().ok()? as usize;
+    let mut subpackets = Reader::new(reader.take(len).ok()?);
+
+    let mut result = Vec::new();
+    while subpackets.remaining() > 0 {
+        let len = subpackets.length().ok()?;
+        let subpacket = subpackets.take(len).ok()?;
+        if !subpacket.is_empty() {
+            result.push((subpacket[0] & 0x7f, &subpacket[1..]));
+        }
+    }
+    Some(result)
+}
+
+struct Packet<'a> {
+    tag: u8,
+    body: &'a [u8],
+}
+
+fn packets<'a>(data: &'a [u8]) -> Result<Vec<Packet<'a>>> {
+    let mut reader = Reader::new(data);
+    let mut packets = Vec::new();
+
+    while reader.remaining() > 0 {
+        let header = reader.u8()?;
+        if header & 0x80 == 0 {
+            bail!("Invalid packet header");
+        }
+
+        let (tag, len) = if header & 0x40 != 0 {
+            (header & 0x3f, reader.length()?)
+        } else {
+            let len = match header & 0x03 {
+                0 => reader.u8()? as usize,
+                1 => reader.u16()? as usize,
+                2 => reader.u32()? as usize,
+                _ => bail!("Packets of indeterminate length are not supported"),
+            };
+            ((header >> 2) & 0x0f, len)
+        };
+
+        packets.push(Packet {
+            tag,
+            body: reader.take(len)?,
+        });
+    }
+
+    Ok(packets)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.data.len() {
+            bail!("Unexpected end of data");
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from(b[0]) << 8 | u16::from(b[1]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(b.iter().fold(0, |n, &b| n << 8 | u32::from(b)))
+    }
+
+    /// Reads a new format packet or subpacket length, partial lengths aren't supported.
+    fn length(&mut self) -> Result<usize> {
+        match self.u8()? {
+            n @ 0..=191 => Ok(n as usize),
+            n @ 192..=223 => Ok(((n as usize - 192) << 8) + self.u8()? as usize + 192),
+            255 => Ok(self.u32()? as usize),
+            _ => bail!("Partial body lengths are not supported"),
+        }
+    }
+
+    fn mpi(&mut self) -> Result<&'a [u8]> {
+        let bits = self.u16()? as usize;
+        self.take((bits + 7) / 8)
+    }
+}
+
+fn be16(n: usize) -> [u8; 2] {
+    [(n >> 8) as u8, n as u8]
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn packet_length(len: usize) -> Vec<u8> {
+    match len {
+        0..=191 => vec![len as u8],
+        192..=8383 => vec![(((len - 192) >> 8) + 192) as u8, (len - 192) as u8],
+        _ => {
+            let mut bytes = vec![255];
+            bytes.extend_from_slice(&be32(len as u32));
+            bytes
+        }
+    }
+}
+
+fn subpacket(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    out.extend_from_slice(&packet_length(data.len() + 1));
+    out.push(kind);
+    out.extend_from_slice(data);
+}
+
+fn mpi(value: &[u8]) -> Vec<u8> {
+    let start = value.iter().position(|&b| b != 0).unwrap_or(value.len());
+    let value = &value[start..];
+    let bits = value
+        .first()
+        .map_or(0, |&b| (value.len() - 1) * 8 + (8 - b.leading_zeros() as usize));
+
+    let mut out = be16(bits).to_vec();
+    out.extend_from_slice(value);
+    out
+}
+
+/// CRC-24 of the armor checksum (RFC 4880, section 6.1).
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc = 0x00b7_04ce_u32;
+    for &b in data {
+        crc ^= u32::from(b) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x0100_0000 != 0 {
+                crc ^= 0x0186_4cfb;
+            }
+        }
+    }
+    crc & 0x00ff_ffff
+}
+
+fn armor(kind: &str, data: &[u8]) -> String {
+    let encoded = ::base64::encode(data);
+    let crc = crc24(data);
+
+    let mut armored = format!("-----BEGIN {}-----\n\n", kind);
+    for line in encoded.as_bytes().chunks(64) {
+        armored.push_str(&String::from_utf8_lossy(line));
+        armored.push('\n');
+    }
+    armored.push('=');
+    armored.push_str(&::base64::encode(&[(crc >> 16) as u8, (crc >> 8) as u8, crc as u8]));
+    armored.push_str(&format!("\n-----END {}-----\n", kind));
+    armored
+}
+
+fn dearmor(armored: &str, kind: &str) -> Result<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", kind);
+    let end = format!("-----END {}-----", kind);
+
+    let mut lines = armored
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != begin)
+        .skip(1);
+
+    // armor headers like "Comment: ..." end with an empty line
+    for line in &mut lines {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let mut encoded = String::new();
+    let mut checksum = None;
+    for line in lines {
+        if line == end {
+            let data = ::base64::decode(&encoded).chain_err(|| "Invalid armor")?;
+            if let Some(checksum) = checksum {
+                let crc = crc24(&data);
+                if checksum != [(crc >> 16) as u8, (crc >> 8) as u8, crc as u8] {
+                    bail!("Invalid armor checksum");
+                }
+            }
+            return Ok(data);
+        } else if line.starts_with('=') {
+            checksum = Some(::base64::decode(&line[1..]).chain_err(|| "Invalid armor checksum")?);
+        } else {
+            encoded.push_str(line);
+        }
+    }
+
+    bail!("No {} found", kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Verifies a signature created by `sign` with the public part of `key`.
+    fn verify(key: &SecretKey, text: &[u8], signature: &[u8]) -> bool {
+        use openssl::hash::MessageDigest;
+        use openssl::sign::Verifier;
+        use sha2::{Digest, Sha256};
+
+        let packets = packets(signature).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].tag, TAG_SIGNATURE);
+
+        let mut reader = Reader::new(packets[0].body);
+        reader.take(4).unwrap();
+        let hashed_len = reader.u16().unwrap() as usize;
+        let hashed = &packets[0].body[..6 + hashed_len];
+        reader.take(hashed_len).unwrap();
+        let unhashed_len = reader.u16().unwrap() as usize;
+        reader.take(unhashed_len + 2).unwrap();
+
+        let mut data = text.to_vec();
+        data.extend_from_slice(hashed);
+        data.extend_from_slice(&[4, 0xff]);
+        data.extend_from_slice(&be32(hashed.len() as u32));
+
+        let public = PKey::public_key_from_der(&key.key.public_key_to_der().unwrap()).unwrap();
+        if key.algorithm == ALGORITHM_EDDSA {
+            let mut signature = [0; 64];
+            let r = reader.mpi().unwrap();
+            signature[32 - r.len()..32].copy_from_slice(r);
+            let s = reader.mpi().unwrap();
+            signature[64 - s.len()..].copy_from_slice(s);
+
+            let mut verifier = Verifier::new_without_digest(&public).unwrap();
+            verifier.verify_oneshot(&signature, &Sha256::digest(&data)).unwrap()
+        } else {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &public).unwrap();
+            verifier.update(&data).unwrap();
+            verifier.verify(reader.mpi().unwrap()).unwrap()
+        }
+    }
+
+    #[test]
+    fn canonical_text() {
+        let (escaped, canonical) = cleartext("user: test \n-----BEGIN\r\nzone\n");
+
+        assert_eq!(escaped, "user: test \n- -----BEGIN\nzone");
+        assert_eq!(canonical, b"user: test\r\n-----BEGIN\r\nzone".to_vec());
+    }
+
+    #[test]
+    fn signatures_verify() {
+        for &(file, fingerprint) in &[
+            ("res/test/ed25519.asc", "8B659F12A16F8B570CD1FB6497694FA1CEEFC718"),
+            // the signing subkey is preferred over the primary key
+            ("res/test/rsa.asc", "687621271660A7D4F7CB83FA2A06555090C6E8EB"),
+        ] {
+            let builder = NativeSignedMessageBuilder::load(file, None).unwrap();
+            assert_eq!(builder.fingerprint(), fingerprint);
+
+            let signature = builder.key.sign(b"user: test\r\ndomain: example.com", 1_500_000_000).unwrap();
+            assert!(verify(&builder.key, b"user: test\r\ndomain: example.com", &signature), "{}", file);
+            assert!(!verify(&builder.key, b"user: test\r\ndomain: example.org", &signature), "{}", file);
+        }
+    }
+
+    #[test]
+    fn protected_key() {
+        let builder = NativeSignedMessageBuilder::load("res/test/protected.asc", Some("res/test/passphrase")).unwrap();
+        assert_eq!(builder.fingerprint(), "D590CC32F3E23866A59EEA72486AF83004B50137");
+
+        let signature = builder.key.sign(b"user: test", 1_500_000_000).unwrap();
+        assert!(verify(&builder.key, b"user: test", &signature));
+
+        let data = dearmor(&read_file("res/test/protected.asc").unwrap(), "PGP PRIVATE KEY BLOCK").unwrap();
+        let packets = packets(&data).unwrap();
+        let body = signing_key(&packets, 1_500_000_000).unwrap();
+        assert!(SecretKey::parse(body, Some(b"correct horse")).is_ok());
+        assert!(SecretKey::parse(body, Some(b"wrong horse")).is_err());
+        assert!(SecretKey::parse(body, None).is_err());
+    }
+
+    fn subkey(created: u32) -> Vec<u8> {
+        let mut body = vec![4];
+        body.extend_from_slice(&be32(created));
+        body
+    }
+
+    fn signature(signature_type: u8, subpackets: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut hashed = Vec::new();
+        for &(kind, data) in subpackets {
+            subpacket(&mut hashed, kind, data);
+        }
+        let mut body = vec![4, signature_type, ALGORITHM_EDDSA, HASH_SHA256];
+        body.extend_from_slice(&be16(hashed.len()));
+        body.extend_from_slice(&hashed);
+        body
+    }
+
+    fn packet<'a>(tag: u8, body: &'a [u8]) -> Packet<'a> {
+        Packet { tag, body }
+    }
+
+    #[test]
+    fn signing_subkey_selection() {
+        let primary = subkey(1000);
+        let (older, newer, encrypting) = (subkey(2000), subkey(3000), subkey(4000));
+        let sign = [KEY_FLAG_SIGN];
+        let binding = signature(SIGNATURE_SUBKEY_BINDING, &[(SUBPACKET_KEY_FLAGS, &sign)]);
+        let encrypt = signature(SIGNATURE_SUBKEY_BINDING, &[(SUBPACKET_KEY_FLAGS, &[0x0c])]);
+        let expiring = signature(
+            SIGNATURE_SUBKEY_BINDING,
+            &[(SUBPACKET_KEY_FLAGS, &sign), (SUBPACKET_KEY_EXPIRATION_TIME, &be32(1000))],
+        );
+        let revocation = signature(SIGNATURE_SUBKEY_REVOCATION, &[]);
+
+        let select = |newer_signatures: &[&Vec<u8>], now| {
+            let mut packets = vec![
+                packet(TAG_SECRET_KEY, &primary),
+                packet(TAG_SECRET_SUBKEY, &newer),
+            ];
+            packets.extend(newer_signatures.iter().map(|body| packet(TAG_SIGNATURE, body)));
+            // the key order doesn't matter, only the creation time
+            packets.push(packet(TAG_SECRET_SUBKEY, &older));
+            packets.push(packet(TAG_SIGNATURE, &binding));
+            packets.push(packet(TAG_SECRET_SUBKEY, &encrypting));
+            packets.push(packet(TAG_SIGNATURE, &encrypt));
+            signing_key(&packets, now).unwrap().to_vec()
+        };
+
+        assert_eq!(select(&[&binding], 5000), newer);
+        assert_eq!(select(&[&expiring], 3999), newer);
+        assert_eq!(select(&[&expiring], 4000), older);
+        assert_eq!(select(&[&binding, &revocation], 5000), older);
+        assert_eq!(select(&[&encrypt], 5000), older);
+        assert_eq!(select(&[], 5000), older);
+        assert_eq!(signing_key(&[packet(TAG_SECRET_KEY, &primary)], 5000).unwrap(), &primary[..]);
+    }
+
+    #[test]
+    fn message_format() {
+        let builder = NativeSignedMessageBuilder::load("res/test/ed25519.asc", None).unwrap();
+        let message = builder.sign("user: test\n").unwrap();
+
+        assert!(message.starts_with("-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\nuser: test\n-----BEGIN PGP SIGNATURE-----\n\n"));
+        assert!(message.ends_with("-----END PGP SIGNATURE-----\n"));
+
+        let signature = dearmor(&message, "PGP SIGNATURE").unwrap();
+        assert!(verify(&builder.key, b"user: test", &signature));
+    }
+
+    #[test]
+    fn armor_roundtrip() {
+        let data: Vec<u8> = (0..200).collect();
+        let armored = armor("PGP SIGNATURE", &data);
+
+        assert_eq!(dearmor(&armored, "PGP SIGNATURE").unwrap(), data);
+        assert!(dearmor(&armored.replacen("AAEC", "AAED", 1), "PGP SIGNATURE").is_err());
+        assert!(dearmor(&armored, "PGP PRIVATE KEY BLOCK").is_err());
+    }
+}
@@ -1,33 +1,243 @@
-use config::Config;
+use config::GpgConfig;
 use errors::*;
+use std::io::{self, Read};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Interval in which a running gpg is checked for its exit.
+const WAIT_POLL_MILLIS: u64 = 10;
 
 pub trait SignedMessageBuilder: Send + Sync + 'static {
     fn sign(&self, text: &str) -> Result<String>;
 }
 
-pub struct Sha1SignedMessageBuilder {
-    key: String,
+impl<T: SignedMessageBuilder + ?Sized> SignedMessageBuilder for Box<T> {
+    fn sign(&self, text: &str) -> Result<String> {
+        (**self).sign(text)
+    }
 }
 
-impl Sha1SignedMessageBuilder {
-    pub fn new(config: &Config) -> Self {
-        Sha1SignedMessageBuilder {
-            key: config.pgp_key.clone(),
-        }
+impl<T: SignedMessageBuilder + ?Sized> SignedMessageBuilder for Arc<T> {
+    fn sign(&self, text: &str) -> Result<String> {
+        (**self).sign(text)
     }
 }
 
-impl SignedMessageBuilder for Sha1SignedMessageBuilder {
-    fn sign(&self, text: &str) -> Result<String> {
-        use std::io::Write;
-        use std::process::{Command, Stdio};
+/// Text signed and verified by the startup checks.
+static PREFLIGHT_TEXT: &str = "dyndns-daemon preflight\n";
+
+/// Key which gpg signs with, found by the startup checks.
+#[derive(Debug, PartialEq)]
+pub struct SigningKey {
+    /// Fingerprint of the primary key or subkey which signs
+    pub fingerprint: String,
+    /// Expiration as Unix time, the earlier one of the primary key and the signing key
+    pub expires: Option<i64>,
+}
+
+/// Creates cleartext signatures with the `gpg` binary.
+pub struct GpgSignedMessageBuilder {
+    config: GpgConfig,
+}
+
+impl GpgSignedMessageBuilder {
+    pub fn new(config: &GpgConfig) -> Self {
+        GpgSignedMessageBuilder {
+            config: config.clone(),
+        }
+    }
 
-        let mut cmd = Command::new("gpg");
+    /// Checks that gpg runs, that the secret key exists and isn't expired at `now`,
+    /// and that a test signature verifies.
+    pub fn preflight(&self, now: i64) -> Result<SigningKey> {
+        let mut cmd = self.command();
+        cmd.arg("--version");
+        let version = self.run(cmd, b"").chain_err(|| "gpg is not runnable")?;
+        if !version.starts_with("gpg") {
+            bail!("{} is no gpg binary", self.config.binary);
+        }
+
+        let mut cmd = self.command();
+        cmd.arg("--with-colons");
+        cmd.arg("--list-secret-keys").arg(&self.config.key);
+        let listing = self.run(cmd, b"")
+            .chain_err(|| format!("Secret key {} not found", self.config.key))?;
+        let key = signing_key(&listing, now).chain_err(|| format!("Secret key {} is unusable", self.config.key))?;
+
+        let message = self.sign(PREFLIGHT_TEXT).chain_err(|| "Error creating test signature")?;
+        self.verify(&message, &key.fingerprint)
+            .chain_err(|| "Error verifying test signature")?;
+
+        Ok(key)
+    }
+
+    /// Checks that `message` carries a valid signature of the key `fingerprint`.
+    fn verify(&self, message: &str, fingerprint: &str) -> Result<()> {
+        let mut cmd = self.command();
+        cmd.arg("--status-fd").arg("1");
+        cmd.arg("--verify");
+        let status = self.run(cmd, message.as_bytes())?;
+
+        let valid = status.lines().any(|line| {
+            let mut fields = line.split(' ');
+            fields.next() == Some("[GNUPG:]") && fields.next() == Some("VALIDSIG")
+                && fields.next().map_or(false, |f| f.eq_ignore_ascii_case(fingerprint))
+        });
+        if !valid {
+            bail!("No valid signature by {}", fingerprint);
+        }
+        Ok(())
+    }
+
+    /// gpg with the options shared by all invocations.
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.config.binary);
 
         // not interactive and no output to terminal
         cmd.arg("--batch");
         cmd.arg("--no-tty");
 
+        // keyring of the configured directory, not of whoever runs the daemon
+        if let Some(ref homedir) = self.config.homedir {
+            cmd.arg("--homedir").arg(homedir);
+        }
+
+        cmd
+    }
+
+    /// Runs `cmd` with `input` on stdin and returns its stdout. The pipes are served by
+    /// their own threads, so gpg never blocks on a full pipe. After the configured timeout
+    /// gpg is killed along with its process group, and helpers which keep the pipes open
+    /// aren't waited for any longer.
+    fn run(&self, mut cmd: Command, input: &[u8]) -> Result<String> {
+        use std::io::Write;
+        use std::process::Stdio;
+        use std::time::Instant;
+
+        cmd.stdin(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        own_process_group(&mut cmd);
+
+        let mut child = cmd
+            .spawn()
+            .chain_err(|| ErrorKind::GpgSpawn(self.config.binary.clone()))?;
+        let group = child.id() as ::libc::pid_t;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| "Error retrieving stdin for gpg")?;
+        let input = input.to_vec();
+        let writer = background(move || stdin.write_all(&input));
+        let stdout = read_to_end(child.stdout.take())?;
+        let stderr = read_to_end(child.stderr.take())?;
+
+        let deadline = Instant::now() + Duration::from_secs(self.config.timeout);
+        let status = loop {
+            if let Some(status) = child.try_wait().chain_err(|| "Error waiting for gpg")? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                kill_group(group);
+                let _ = child.wait();
+                bail!(ErrorKind::GpgTimeout(self.config.timeout));
+            }
+            thread::sleep(Duration::from_millis(WAIT_POLL_MILLIS));
+        };
+
+        // A helper gpg started may still hold the pipes after gpg exited. It is killed
+        // at the deadline, and the pipe threads are left to end on their own.
+        let (stdout, stderr) = match (receive(&stdout, deadline)?, receive(&stderr, deadline)?) {
+            (Some(stdout), Some(stderr)) => (
+                stdout.chain_err(|| "Error reading gpg output")?,
+                stderr.chain_err(|| "Error reading gpg output")?,
+            ),
+            _ => {
+                kill_group(group);
+                bail!(ErrorKind::GpgTimeout(self.config.timeout));
+            }
+        };
+        if !status.success() {
+            bail!(ErrorKind::GpgFailed(
+                status.to_string(),
+                String::from_utf8_lossy(&stderr).into_owned()
+            ));
+        }
+
+        // gpg succeeded, so it read all of the input
+        match receive(&writer, deadline)? {
+            Some(written) => written.chain_err(|| "Error writing text to stdin")?,
+            None => {
+                kill_group(group);
+                bail!(ErrorKind::GpgTimeout(self.config.timeout));
+            }
+        }
+
+        String::from_utf8(stdout).chain_err(|| "invalid utf - 8 in gpg output")
+    }
+}
+
+/// Starts `cmd` in a process group of its own, so it can be killed with its children.
+fn own_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        cmd.pre_exec(|| {
+            if ::libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+}
+
+fn kill_group(group: ::libc::pid_t) {
+    unsafe { ::libc::kill(-group, ::libc::SIGKILL) };
+}
+
+/// Runs `f` in a new thread, whose result can be awaited with a timeout.
+fn background<T, F>(f: F) -> mpsc::Receiver<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+    receiver
+}
+
+/// Reads `pipe` to its end in a new thread.
+fn read_to_end<R: Read + Send + 'static>(pipe: Option<R>) -> Result<mpsc::Receiver<io::Result<Vec<u8>>>> {
+    let mut pipe = pipe.ok_or("Error retrieving output pipe of gpg")?;
+    Ok(background(move || {
+        let mut buffer = Vec::new();
+        pipe.read_to_end(&mut buffer).map(|_| buffer)
+    }))
+}
+
+/// Waits for the result of a `background` thread until `deadline`, `None` if it didn't finish.
+fn receive<T>(result: &mpsc::Receiver<T>, deadline: ::std::time::Instant) -> Result<Option<T>> {
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Instant;
+
+    match result.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => Ok(Some(result)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => bail!("gpg pipe thread panicked"),
+    }
+}
+
+impl SignedMessageBuilder for GpgSignedMessageBuilder {
+    fn sign(&self, text: &str) -> Result<String> {
+        let mut cmd = self.command();
+
         // armor
         cmd.arg("-a");
 
@@ -35,41 +245,225 @@ impl SignedMessageBuilder for Sha1SignedMessageBuilder {
         cmd.arg("-o").arg("-");
 
         // key
-        cmd.arg("--default-key").arg(&self.key);
+        cmd.arg("--default-key").arg(&self.config.key);
+
+        // the same hash regardless of the key and the gpg defaults
+        cmd.arg("--digest-algo").arg(self.config.digest_algo.name());
+
+        // gpg reads the passphrase from the file, only its path is on the command line
+        if let Some(ref passphrase_file) = self.config.passphrase_file {
+            cmd.arg("--pinentry-mode").arg("loopback");
+            cmd.arg("--passphrase-file").arg(passphrase_file);
+        }
 
         // cleartext signature
         cmd.arg("--clearsign");
 
-        cmd.stdin(Stdio::piped());
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+        self.run(cmd, text.as_bytes())
+    }
+}
+
+/// A key of a `--with-colons` listing.
+struct ListedKey {
+    fingerprint: String,
+    created: i64,
+    expires: Option<i64>,
+    can_sign: bool,
+    usable: bool,
+}
 
-        let mut child = cmd.spawn().chain_err(|| "Error spawning gpg")?;
+/// Picks the key gpg signs with from the `--with-colons --list-secret-keys` output of the
+/// first matching key: the newest usable subkey which may sign, otherwise the primary key.
+fn signing_key(listing: &str, now: i64) -> Result<SigningKey> {
+    let mut keys: Vec<ListedKey> = Vec::new();
 
-        {
-            let stdin = child
-                .stdin
-                .as_mut()
-                .ok_or_else(|| "Error retrieving stdin for gpg")?;
-            stdin
-                .write_all(text.as_bytes())
-                .chain_err(|| "Error writing text to stdin")?;
+    for line in listing.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        let field = |i: usize| fields.get(i).cloned().unwrap_or("");
+
+        match field(0) {
+            "sec" if !keys.is_empty() => break,
+            "sec" | "ssb" => {
+                let expires = field(6).parse().ok();
+                keys.push(ListedKey {
+                    fingerprint: String::new(),
+                    created: field(5).parse().unwrap_or(0),
+                    expires,
+                    can_sign: field(11).contains('s'),
+                    // expired, revoked, invalid or disabled, "#" marks a missing secret key
+                    usable: !["e", "r", "i", "d"].contains(&field(1))
+                        && expires.map_or(true, |expires| expires > now)
+                        && field(14) != "#",
+                });
+            }
+            "fpr" => if let Some(key) = keys.last_mut() {
+                if key.fingerprint.is_empty() {
+                    key.fingerprint = field(9).to_owned();
+                }
+            },
+            _ => (),
         }
+    }
 
-        let output = child
-            .wait_with_output()
-            .chain_err(|| "Error waiting for output")?;
+    let primary = match keys.first() {
+        Some(primary) => primary,
+        None => bail!("No secret key listed"),
+    };
+    if !primary.usable {
+        bail!("Key {} is expired, revoked or unavailable", primary.fingerprint);
+    }
 
-        match output.status.code() {
-            Some(0) => (),
-            Some(i) => bail!(
-                "GPG exited with code: {}\nstderr: {}",
-                i,
-                String::from_utf8(output.stderr).unwrap_or_else(|_| "".to_owned())
-            ),
-            None => bail!("No exit code"),
-        };
+    let signing = keys.iter()
+        .filter(|key| key.can_sign && key.usable)
+        .max_by_key(|key| key.created)
+        .ok_or("No usable key which may sign")?;
+
+    let expires = match (primary.expires, signing.expires) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    Ok(SigningKey {
+        fingerprint: signing.fingerprint.clone(),
+        expires,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::DigestAlgo;
+
+    fn builder(timeout: u64) -> GpgSignedMessageBuilder {
+        GpgSignedMessageBuilder::new(&GpgConfig {
+            key: "test@example.com".to_owned(),
+            passphrase_file: None,
+            binary: "gpg".to_owned(),
+            homedir: None,
+            digest_algo: DigestAlgo::Sha256,
+            expiry_warning_days: 30,
+            timeout,
+        })
+    }
+
+    #[test]
+    fn large_input_does_not_block() {
+        // more than a pipe buffer in both directions
+        let input = "user: test\n".repeat(100_000);
+        let output = builder(10).run(Command::new("cat"), input.as_bytes()).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn hung_process_is_killed() {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("10");
+
+        match builder(1).run(cmd, b"").unwrap_err().kind() {
+            ErrorKind::GpgTimeout(1) => (),
+            kind => panic!("unexpected error {}", kind),
+        }
+    }
+
+    #[test]
+    fn helpers_are_killed() {
+        use std::fs;
+
+        let pid_file = ::std::env::temp_dir().join(format!("dyndns-daemon-helper-{}", ::std::process::id()));
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!("sleep 10 & echo $! > {}; wait", pid_file.display()));
+
+        assert!(builder(1).run(cmd, b"").is_err());
+
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        fs::remove_file(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let running = (0..100).all(|_| {
+            thread::sleep(Duration::from_millis(WAIT_POLL_MILLIS));
+            // killed, maybe not reaped yet
+            fs::read_to_string(&stat).map_or(false, |stat| !stat.contains(") Z "))
+        });
+        assert!(!running, "helper {} survived", pid.trim());
+    }
+
+    #[test]
+    fn open_pipes_of_helpers_are_not_waited_for() {
+        use std::time::Instant;
+
+        // gpg exits at once, the helper keeps stdout open
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("sleep 10 & exit 0");
+
+        let start = Instant::now();
+        match builder(1).run(cmd, b"").unwrap_err().kind() {
+            ErrorKind::GpgTimeout(1) => (),
+            kind => panic!("unexpected error {}", kind),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn failure_contains_stderr() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo 'no secret key' >&2; exit 2");
+
+        match builder(10).run(cmd, b"").unwrap_err().kind() {
+            ErrorKind::GpgFailed(status, stderr) => {
+                assert!(status.contains('2'), "{}", status);
+                assert_eq!(stderr, "no secret key\n");
+            }
+            kind => panic!("unexpected error {}", kind),
+        }
+    }
+
+    static LISTING: &str = "\
+sec:u:2048:1:100F89DA5CD15168:1500000000:1900000000::u:::scSC:::+:::23::0:
+fpr:::::::::7BD2C496BDBD452FE56668CC100F89DA5CD15168:
+grp:::::::::F9D8913926B65805CD138C911E92666E8EBFD959:
+uid:u::::1500000000::621FC808DEA7E62E958893D26AEAD252C4CA8122::test <rsa@example.com>::::::::::0:
+ssb:e:2048:1:1111111111111111:1500000000:1600000000:::::s:::+:::23:
+fpr:::::::::1111111111111111111111111111111111111111:
+ssb:u:2048:1:2A06555090C6E8EB:1600000000:1800000000:::::s:::+:::23:
+fpr:::::::::687621271660A7D4F7CB83FA2A06555090C6E8EB:
+ssb:u:2048:1:3333333333333333:1700000000::::::e:::+:::23:
+fpr:::::::::3333333333333333333333333333333333333333:
+sec:u:2048:1:4444444444444444:1500000000::::u:::scSC:::+:::23::0:
+fpr:::::::::4444444444444444444444444444444444444444:
+";
+
+    #[test]
+    fn newest_usable_signing_subkey() {
+        assert_eq!(
+            signing_key(LISTING, 1_700_000_000).unwrap(),
+            SigningKey {
+                fingerprint: "687621271660A7D4F7CB83FA2A06555090C6E8EB".to_owned(),
+                expires: Some(1_800_000_000),
+            }
+        );
+    }
+
+    #[test]
+    fn primary_key_signs_without_subkey() {
+        let listing = LISTING.replace(":s:::+", ":e:::+");
+        assert_eq!(
+            signing_key(&listing, 1_700_000_000).unwrap(),
+            SigningKey {
+                fingerprint: "7BD2C496BDBD452FE56668CC100F89DA5CD15168".to_owned(),
+                expires: Some(1_900_000_000),
+            }
+        );
+    }
 
-        String::from_utf8(output.stdout).chain_err(|| "invalid utf - 8 in signed message")
+    #[test]
+    fn unusable_keys() {
+        // the signing subkey expired, the primary key may sign itself
+        assert_eq!(
+            signing_key(LISTING, 1_850_000_000).unwrap().fingerprint,
+            "7BD2C496BDBD452FE56668CC100F89DA5CD15168"
+        );
+        assert!(signing_key(LISTING, 1_900_000_000).is_err());
+        assert!(signing_key(&LISTING.replace("sec:u", "sec:r"), 1_700_000_000).is_err());
+        assert!(signing_key(&LISTING.replace("scSC:::+", "scSC:::#"), 1_700_000_000).is_err());
+        assert!(signing_key("", 1_700_000_000).is_err());
     }
 }
@@ -0,0 +1,279 @@
+use errors::*;
+use state::write_atomically;
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// An update whose delivery failed and which waits for the next attempt.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingUpdate {
+    /// Newest undelivered IPv4 address
+    #[serde(default)]
+    pub ipv4: Option<Ipv4Addr>,
+    /// Newest undelivered IPv6 address
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Addr>,
+    pub attempts: u32,
+    pub next_attempt: i64,
+}
+
+impl PendingUpdate {
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.ipv4
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(self.ipv6.map(IpAddr::V6))
+            .collect()
+    }
+}
+
+impl fmt::Display for PendingUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addresses: Vec<String> = self.addresses().iter().map(IpAddr::to_string).collect();
+        write!(f, "{}", addresses.join(", "))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct OutboxFile {
+    pending: BTreeMap<String, PendingUpdate>,
+}
+
+/// Keeps the newest undelivered address of each domain and family, optionally persisted to disk.
+pub struct Outbox {
+    path: Option<PathBuf>,
+    initial_delay: i64,
+    max_delay: i64,
+    entries: Mutex<OutboxFile>,
+}
+
+impl Outbox {
+    pub fn load<P: AsRef<Path>>(path: Option<P>, initial_delay: u64, max_delay: u64) -> Result<Self> {
+        use std::fs::File;
+        use std::io::{ErrorKind, Read};
+        use toml::de::from_str;
+
+        let path = path.map(|p| p.as_ref().to_path_buf());
+
+        let entries = match path {
+            Some(ref path) => match File::open(path) {
+                Ok(mut file) => {
+                    let mut buffer = String::new();
+                    file.read_to_string(&mut buffer)
+                        .chain_err(|| "Error reading outbox")?;
+                    from_str(&buffer).chain_err(|| "Error parsing outbox")?
+                }
+                Err(ref e) if e.kind() == ErrorKind::NotFound => OutboxFile::default(),
+                Err(e) => return Err(e).chain_err(|| "Error opening outbox"),
+            },
+            None => OutboxFile::default(),
+        };
+
+        Ok(Outbox {
+            path,
+            initial_delay: initial_delay as i64,
+            max_delay: max_delay as i64,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    fn modify<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut BTreeMap<String, PendingUpdate>) -> T,
+    {
+        use toml::ser::to_string;
+
+        let mut entries = self.entries.lock().map_err(|_| "Outbox lock poisoned")?;
+        let result = f(&mut entries.pending);
+
+        if let Some(ref path) = self.path {
+            let content = to_string(&*entries).chain_err(|| "Error serializing outbox")?;
+            write_atomically(path, &content).chain_err(|| "Error writing outbox")?;
+        }
+
+        Ok(result)
+    }
+
+    fn delay(&self, attempts: u32) -> i64 {
+        let factor = if attempts < 62 {
+            1i64 << attempts
+        } else {
+            i64::max_value()
+        };
+        cmp::min(self.initial_delay.saturating_mul(factor), self.max_delay)
+    }
+
+    pub fn pending_count(&self) -> Result<usize> {
+        let entries = self.entries.lock().map_err(|_| "Outbox lock poisoned")?;
+        Ok(entries.pending.len())
+    }
+
+    /// Queues `addr` for `domain`, replacing an older pending address of the same family.
+    /// The pending address of the other family is kept, the backoff starts anew.
+    pub fn enqueue(&self, domain: &str, addr: &IpAddr, now: i64) -> Result<PendingUpdate> {
+        let next_attempt = now + self.delay(0);
+
+        self.modify(|entries| {
+            let pending = entries.entry(domain.to_owned()).or_insert(PendingUpdate {
+                ipv4: None,
+                ipv6: None,
+                attempts: 0,
+                next_attempt,
+            });
+            match *addr {
+                IpAddr::V4(addr) => pending.ipv4 = Some(addr),
+                IpAddr::V6(addr) => pending.ipv6 = Some(addr),
+            }
+            pending.attempts = 0;
+            pending.next_attempt = next_attempt;
+            pending.clone()
+        })
+    }
+
+    pub fn get(&self, domain: &str) -> Result<Option<PendingUpdate>> {
+        let entries = self.entries.lock().map_err(|_| "Outbox lock poisoned")?;
+        Ok(entries.pending.get(domain).cloned())
+    }
+
+    /// Returns the pending updates whose next attempt is due at `now`.
+    pub fn due(&self, now: i64) -> Result<Vec<(String, PendingUpdate)>> {
+        let entries = self.entries.lock().map_err(|_| "Outbox lock poisoned")?;
+
+        Ok(entries
+            .pending
+            .iter()
+            .filter(|&(_, pending)| pending.next_attempt <= now)
+            .map(|(domain, pending)| (domain.clone(), pending.clone()))
+            .collect())
+    }
+
+    /// Drops the pending addresses of `domain` which a delivered mail carried, a newer
+    /// address queued meanwhile stays. Returns the pending update as it was, if it changed.
+    pub fn delivered(&self, domain: &str, delivered: &[IpAddr]) -> Result<Option<PendingUpdate>> {
+        {
+            let entries = self.entries.lock().map_err(|_| "Outbox lock poisoned")?;
+            if !entries.pending.contains_key(domain) {
+                return Ok(None);
+            }
+        }
+
+        self.modify(|entries| {
+            let before = entries.get(domain)?.clone();
+            let pending = entries.get_mut(domain)?;
+            if pending.ipv4.map_or(false, |addr| delivered.contains(&IpAddr::V4(addr))) {
+                pending.ipv4 = None;
+            }
+            if pending.ipv6.map_or(false, |addr| delivered.contains(&IpAddr::V6(addr))) {
+                pending.ipv6 = None;
+            }
+
+            let changed = *pending != before;
+            if pending.ipv4.is_none() && pending.ipv6.is_none() {
+                entries.remove(domain);
+            }
+            if changed {
+                Some(before)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Reschedules the pending update of `domain` after a failed attempt.
+    pub fn failed(&self, domain: &str, now: i64) -> Result<Option<PendingUpdate>> {
+        self.modify(|entries| {
+            entries.get_mut(domain).map(|pending| {
+                pending.attempts = pending.attempts.saturating_add(1);
+                pending.next_attempt = now + self.delay(pending.attempts);
+                pending.clone()
+            })
+        })
+    }
+
+    /// Writes the outbox again, e.g. when a previous write failed.
+    pub fn flush(&self) -> Result<()> {
+        self.modify(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn only_newest_update_is_kept() {
+        let outbox = Outbox::load(None::<PathBuf>, 60, 3600).unwrap();
+
+        outbox.enqueue("example.com", &"192.0.2.1".parse().unwrap(), 0).unwrap();
+        outbox.enqueue("example.com", &"192.0.2.2".parse().unwrap(), 0).unwrap();
+        outbox.enqueue("example.org", &"192.0.2.3".parse().unwrap(), 0).unwrap();
+
+        assert_eq!(outbox.pending_count().unwrap(), 2);
+        assert!(outbox.due(59).unwrap().is_empty());
+
+        let due = outbox.due(60).unwrap();
+        assert_eq!(due[0].0, "example.com");
+        assert_eq!(due[0].1.addresses(), vec!["192.0.2.2".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn newer_addresses_stay_queued() {
+        let outbox = Outbox::load(None::<PathBuf>, 60, 3600).unwrap();
+        outbox.enqueue("example.com", &"192.0.2.1".parse().unwrap(), 0).unwrap();
+        outbox.enqueue("example.com", &"2001:db8::1".parse().unwrap(), 0).unwrap();
+
+        // a mail rendered before the IPv6 update
+        let before = outbox.delivered("example.com", &["192.0.2.1".parse().unwrap()]).unwrap();
+        assert_eq!(before.unwrap().to_string(), "192.0.2.1, 2001:db8::1");
+        assert_eq!(outbox.get("example.com").unwrap().unwrap().to_string(), "2001:db8::1");
+
+        assert!(outbox.delivered("example.com", &["2001:db8::2".parse().unwrap()]).unwrap().is_none());
+        outbox.delivered("example.com", &["2001:db8::1".parse().unwrap()]).unwrap();
+        assert_eq!(outbox.pending_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let outbox = Outbox::load(None::<PathBuf>, 60, 300).unwrap();
+        outbox.enqueue("example.com", &"192.0.2.1".parse().unwrap(), 0).unwrap();
+
+        let delays: Vec<i64> = (0..5)
+            .map(|_| outbox.failed("example.com", 0).unwrap().unwrap().next_attempt)
+            .collect();
+
+        assert_eq!(delays, vec![120, 240, 300, 300, 300]);
+        assert_eq!(outbox.delay(200), 300);
+    }
+
+    #[test]
+    fn outbox_survives_reload() {
+        let path = env::temp_dir().join(format!("dyndns-daemon-outbox-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let outbox = Outbox::load(Some(&path), 60, 3600).unwrap();
+            outbox.enqueue("example.com", &"192.0.2.1".parse().unwrap(), 0).unwrap();
+            outbox.enqueue("example.com", &"2001:db8::1".parse().unwrap(), 0).unwrap();
+            outbox.enqueue("example.org", &"192.0.2.1".parse().unwrap(), 0).unwrap();
+            outbox.delivered("example.org", &["192.0.2.1".parse().unwrap()]).unwrap();
+        }
+
+        // the IPv6 update didn't replace the IPv4 one
+        let outbox = Outbox::load(Some(&path), 60, 3600).unwrap();
+        let due = outbox.due(60).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(
+            due[0].1.addresses(),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap(), "2001:db8::1".parse().unwrap()]
+        );
+        assert_eq!(due[0].1.to_string(), "192.0.2.1, 2001:db8::1");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
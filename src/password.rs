@@ -0,0 +1,204 @@
+use errors::*;
+use std::fmt;
+
+/// Password of an HTTP user, either in plaintext or as hash.
+#[derive(Clone)]
+pub enum Password {
+    Plain(String),
+    /// `$2a$`, `$2b$` or `$2y$` as written by `htpasswd -B`
+    Bcrypt(String),
+    /// PHC string format, e.g. `$argon2id$v=19$...`
+    Argon2(String),
+    /// Unsalted SHA-1 as written by `htpasswd -s`, deprecated
+    Sha1(Vec<u8>),
+}
+
+impl Password {
+    /// Recognizes the hash format by its prefix.
+    pub fn from_hash(hash: &str) -> Result<Self> {
+        let password = if hash.starts_with("$2a$") || hash.starts_with("$2b$")
+            || hash.starts_with("$2y$")
+        {
+            Password::Bcrypt(hash.to_owned())
+        } else if hash.starts_with("$argon2") {
+            Password::Argon2(hash.to_owned())
+        } else if hash.starts_with("{SHA}") {
+            let digest = ::base64::decode(&hash["{SHA}".len()..]).chain_err(|| "Invalid SHA-1 hash")?;
+            if digest.len() != 20 {
+                bail!("Invalid SHA-1 hash");
+            }
+            Password::Sha1(digest)
+        } else if hash.starts_with("$apr1$") {
+            bail!("MD5 hashes are not supported, use bcrypt (htpasswd -B) instead");
+        } else {
+            bail!("Unknown hash format");
+        };
+
+        // malformed hashes would otherwise only show up as failed logins
+        match password {
+            Password::Bcrypt(ref hash) => {
+                ::bcrypt::verify("", hash).chain_err(|| "Invalid bcrypt hash")?;
+            }
+            Password::Argon2(ref hash) => {
+                ::argon2::verify_encoded(hash, b"").chain_err(|| "Invalid argon2 hash")?;
+            }
+            _ => {}
+        }
+
+        Ok(password)
+    }
+
+    /// Checks `password` in constant time.
+    pub fn verify(&self, password: &str) -> bool {
+        match *self {
+            Password::Plain(ref expected) => {
+                compare_secure(&sha256(expected.as_bytes()), &sha256(password.as_bytes()))
+            }
+            Password::Bcrypt(ref hash) => ::bcrypt::verify(password, hash).unwrap_or(false),
+            Password::Argon2(ref hash) => {
+                ::argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+            }
+            Password::Sha1(ref digest) => {
+                use sha1::{Digest, Sha1};
+                compare_secure(digest, &Sha1::digest(password.as_bytes()))
+            }
+        }
+    }
+}
+
+impl Password {
+    /// Checks `password` against a fixed hash of the same format, which takes as long as
+    /// `verify` but never succeeds. Used for unknown users.
+    pub fn verify_dummy(&self, password: &str) {
+        let dummy = match *self {
+            Password::Plain(_) => Password::Plain(String::new()),
+            // the zero digit of the respective base64 alphabet is valid in any position
+            Password::Bcrypt(ref hash) => Password::Bcrypt(dummy_hash(hash, '.')),
+            Password::Argon2(ref hash) => Password::Argon2(dummy_hash(hash, 'A')),
+            Password::Sha1(_) => Password::Sha1(vec![0; 20]),
+        };
+        dummy.verify(password);
+    }
+
+    /// Rough time `verify` takes in nanoseconds, to compare hashes of different formats.
+    pub fn cost(&self) -> u64 {
+        match *self {
+            Password::Plain(_) | Password::Sha1(_) => 1_000,
+            // about 70 ms at cost 10
+            Password::Bcrypt(ref hash) => {
+                let cost: u32 = hash.get(4..6).and_then(|cost| cost.parse().ok()).unwrap_or(0);
+                70_000u64.saturating_mul(1 << cost.min(31))
+            }
+            // about 1 µs per KiB of memory and pass
+            Password::Argon2(ref hash) => {
+                let param = |name: &str| -> u64 {
+                    hash.split('$')
+                        .flat_map(|field| field.split(','))
+                        .find(|param| param.starts_with(name))
+                        .and_then(|param| param[name.len()..].parse().ok())
+                        .unwrap_or(1)
+                };
+                1_000u64.saturating_mul(param("m=")).saturating_mul(param("t="))
+            }
+        }
+    }
+
+    /// Unsalted hashes are cracked easily once the config leaks.
+    pub fn is_deprecated(&self) -> bool {
+        match *self {
+            Password::Sha1(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Keeps the parameters of `hash`, i.e. its cost, but fills the last field, the salt
+/// and digest, with `zero`.
+fn dummy_hash(hash: &str, zero: char) -> String {
+    match hash.rfind('$') {
+        Some(pos) => {
+            let dummy: String = hash[pos + 1..].chars().map(|_| zero).collect();
+            format!("{}{}", &hash[..pos + 1], dummy)
+        }
+        None => hash.to_owned(),
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match *self {
+            Password::Plain(_) => "Plain",
+            Password::Bcrypt(_) => "Bcrypt",
+            Password::Argon2(_) => "Argon2",
+            Password::Sha1(_) => "Sha1",
+        };
+        write!(f, "{}(<redacted>)", kind)
+    }
+}
+
+/// Hashing first makes the comparison independent of the password length.
+fn sha256(data: &[u8]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data).to_vec()
+}
+
+#[inline(never)]
+fn compare_secure(s1: &[u8], s2: &[u8]) -> bool {
+    use consistenttime::ct_u8_slice_eq;
+    ct_u8_slice_eq(s1, s2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext() {
+        let password = Password::Plain("secret".to_owned());
+
+        assert!(password.verify("secret"));
+        assert!(!password.verify("secret "));
+        assert!(!password.verify(""));
+        assert_eq!(format!("{:?}", password), "Plain(<redacted>)");
+    }
+
+    #[test]
+    fn hashes() {
+        let bcrypt = ::bcrypt::hash("secret", 4).unwrap();
+        let argon2 = ::argon2::hash_encoded(b"secret", b"somesalt", &Default::default()).unwrap();
+        let sha1 = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=";
+
+        for hash in &[&bcrypt as &str, &argon2, sha1] {
+            let password = Password::from_hash(hash).unwrap();
+            assert!(password.verify("secret"), "{}", hash);
+            assert!(!password.verify("Secret"), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn dummy_verification() {
+        let bcrypt = ::bcrypt::hash("secret", 4).unwrap();
+        let argon2 = ::argon2::hash_encoded(b"secret", b"somesalt", &Default::default()).unwrap();
+
+        for &(hash, zero) in &[(&bcrypt as &str, '.'), (&argon2, 'A')] {
+            let dummy = dummy_hash(hash, zero);
+            assert_eq!(dummy.len(), hash.len());
+            assert_ne!(&dummy, hash);
+            assert!(!Password::from_hash(&dummy).unwrap().verify("secret"), "{}", dummy);
+        }
+
+        assert!(Password::from_hash("{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=").unwrap().is_deprecated());
+        assert!(Password::from_hash(&bcrypt).unwrap().cost() > Password::Plain("secret".to_owned()).cost());
+        assert!(Password::from_hash(&argon2).unwrap().cost() > Password::Plain("secret".to_owned()).cost());
+        assert!(!Password::from_hash(&bcrypt).unwrap().is_deprecated());
+    }
+
+    #[test]
+    fn invalid_hashes() {
+        assert!(Password::from_hash("secret").is_err());
+        assert!(Password::from_hash("$apr1$salt$hash").is_err());
+        assert!(Password::from_hash("$2y$10$tooshort").is_err());
+        assert!(Password::from_hash("$argon2id$v=19$broken").is_err());
+        assert!(Password::from_hash("{SHA}c2VjcmV0").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Special purpose networks which never belong into a public zone
+/// (RFC 6890 and the IANA special-purpose address registries).
+static BOGONS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "100::/64",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+pub fn bogons() -> Vec<IpNet> {
+    BOGONS
+        .iter()
+        .map(|network| network.parse().expect("Invalid bogon network"))
+        .collect()
+}
+
+/// Checks that `addr` is in none of the `denied` networks and, unless
+/// `allowed` is empty, in one of the `allowed` networks.
+pub fn is_acceptable(addr: &IpAddr, denied: &[IpNet], allowed: &[IpNet]) -> bool {
+    let contains = |networks: &[IpNet]| networks.iter().any(|network| network.contains(addr));
+
+    !contains(denied) && (allowed.is_empty() || contains(allowed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bogons_are_denied() {
+        let bogons = bogons();
+
+        for addr in &["127.0.0.1", "192.168.1.1", "10.1.2.3", "224.0.0.1", "::1", "fe80::1", "fd00::1"] {
+            assert!(!is_acceptable(&ip(addr), &bogons, &[]), "{}", addr);
+        }
+        for addr in &["8.8.8.8", "2a01:4f8::1"] {
+            assert!(is_acceptable(&ip(addr), &bogons, &[]), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn allowed_networks_restrict() {
+        let allowed: Vec<IpNet> = vec!["198.51.100.0/24".parse().unwrap()];
+
+        assert!(is_acceptable(&ip("198.51.100.1"), &[], &allowed));
+        assert!(!is_acceptable(&ip("203.0.113.1"), &[], &allowed));
+        assert!(!is_acceptable(&ip("198.51.100.1"), &bogons(), &allowed));
+    }
+}
@@ -0,0 +1,161 @@
+use errors::*;
+use http::HeaderMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Strips the IPv4 mapping of dual-stack sockets, so IPv4 clients are seen as such.
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..5].iter().all(|&s| s == 0) && segments[5] == 0xffff {
+                ip.to_ipv4().map_or(IpAddr::V6(ip), IpAddr::V4)
+            } else {
+                IpAddr::V6(ip)
+            }
+        }
+        addr => addr,
+    }
+}
+
+pub fn is_trusted(addr: &IpAddr, trusted: &[IpNet]) -> bool {
+    trusted.iter().any(|net| net.contains(addr))
+}
+
+/// Parses a node of a forwarding header, e.g. `192.0.2.1`, `192.0.2.1:4711`
+/// or `"[2001:db8::1]:4711"`. Unknown and obfuscated nodes yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    let addr = if node.starts_with('[') {
+        node[1..].split(']').next()?.parse().ok()?
+    } else if let Ok(addr) = node.parse() {
+        addr
+    } else {
+        let mut parts = node.splitn(2, ':');
+        let addr = parts.next()?.parse().ok()?;
+        parts.next()?.parse::<u16>().ok()?;
+        addr
+    };
+
+    Some(canonical(addr))
+}
+
+/// Returns the `for` parameters of RFC 7239 `Forwarded` header values.
+fn forwarded_for<'a>(value: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    value
+        .split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("for") => Some(value),
+                _ => None,
+            }
+        })
+}
+
+/// Collects the hops of all `name` headers, the client first and the nearest proxy last.
+/// `Forwarded` is parsed according to RFC 7239, all other headers as comma
+/// separated list like `X-Forwarded-For`.
+pub fn hops(headers: &HeaderMap, name: &str) -> Result<Vec<Option<IpAddr>>> {
+    let is_forwarded = name.eq_ignore_ascii_case("forwarded");
+    let mut hops = Vec::new();
+
+    for value in headers.get_all(name) {
+        let value = value
+            .to_str()
+            .chain_err(|| format!("Invalid value for {} header", name))?;
+
+        if is_forwarded {
+            hops.extend(forwarded_for(value).map(parse_node));
+        } else {
+            hops.extend(value.split(',').map(parse_node));
+        }
+    }
+
+    Ok(hops)
+}
+
+/// Walks from the nearest hop towards the client and returns the first
+/// address which isn't a trusted proxy.
+pub fn client_addr(hops: &[Option<IpAddr>], trusted: &[IpNet]) -> Result<IpAddr> {
+    for hop in hops.iter().rev() {
+        match *hop {
+            Some(ref addr) if is_trusted(addr, trusted) => continue,
+            Some(addr) => return Ok(addr),
+            None => bail!("Unknown or obfuscated address in forwarding chain"),
+        }
+    }
+
+    // every hop is a trusted proxy, so the first one is the client
+    match hops.first() {
+        Some(&Some(addr)) => Ok(addr),
+        _ => bail!("Empty forwarding chain"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(name: &'static str, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node(" 192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("192.0.2.1:4711"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("::ffff:192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn header_formats() {
+        let xff = headers("x-forwarded-for", &["192.0.2.1, 198.51.100.1", "10.0.0.1"]);
+        assert_eq!(
+            hops(&xff, "X-Forwarded-For").unwrap(),
+            vec![Some(ip("192.0.2.1")), Some(ip("198.51.100.1")), Some(ip("10.0.0.1"))]
+        );
+
+        let forwarded = headers(
+            "forwarded",
+            &["for=192.0.2.1;proto=https, For=\"[2001:db8::1]:4711\";by=10.0.0.1", "for=unknown"],
+        );
+        assert_eq!(
+            hops(&forwarded, "Forwarded").unwrap(),
+            vec![Some(ip("192.0.2.1")), Some(ip("2001:db8::1")), None]
+        );
+
+        assert!(hops(&HeaderMap::new(), "X-Real-IP").unwrap().is_empty());
+    }
+
+    #[test]
+    fn first_untrusted_hop_is_client() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "2001:db8:1::/48".parse().unwrap()];
+
+        let chain = [Some(ip("192.0.2.1")), Some(ip("198.51.100.1")), Some(ip("10.0.0.1"))];
+        assert_eq!(client_addr(&chain, &trusted).unwrap(), ip("198.51.100.1"));
+
+        let internal = [Some(ip("10.0.0.2")), Some(ip("2001:db8:1::1"))];
+        assert_eq!(client_addr(&internal, &trusted).unwrap(), ip("10.0.0.2"));
+
+        let obfuscated = [Some(ip("192.0.2.1")), None, Some(ip("10.0.0.1"))];
+        assert!(client_addr(&obfuscated, &trusted).is_err());
+
+        assert!(client_addr(&[], &trusted).is_err());
+    }
+}
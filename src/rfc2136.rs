@@ -0,0 +1,622 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use slog::Logger;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use config::Rfc2136Config;
+use dns::{DnsService, Update};
+use errors::*;
+
+const HEADER_SIZE: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_TSIG: u16 = 250;
+
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const OPCODE_UPDATE: u16 = 5;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+
+const TSIG_ALGORITHM: &str = "hmac-sha256";
+const TSIG_FUDGE: u16 = 300;
+
+const TIMEOUT_SECS: u64 = 10;
+const MAX_UDP_SIZE: usize = 512;
+
+fn rcode_name(rcode: u16) -> &'static str {
+    match rcode {
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        16 => "BADSIG",
+        17 => "BADKEY",
+        18 => "BADTIME",
+        _ => "unknown",
+    }
+}
+
+fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value >> 8) as u8);
+    buffer.push(value as u8);
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+    push_u16(buffer, (value >> 16) as u16);
+    push_u16(buffer, value as u16);
+}
+
+fn push_u48(buffer: &mut Vec<u8>, value: u64) {
+    push_u16(buffer, (value >> 32) as u16);
+    push_u32(buffer, value as u32);
+}
+
+fn write_u16(buffer: &mut [u8], pos: usize, value: u16) {
+    buffer[pos] = (value >> 8) as u8;
+    buffer[pos + 1] = value as u8;
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Result<u16> {
+    match message.get(pos..pos + 2) {
+        Some(bytes) => Ok((u16::from(bytes[0]) << 8) | u16::from(bytes[1])),
+        None => bail!("Truncated dns message"),
+    }
+}
+
+fn read_u48(message: &[u8], pos: usize) -> Result<u64> {
+    let high = u64::from(read_u16(message, pos)?);
+    let mid = u64::from(read_u16(message, pos + 2)?);
+    let low = u64::from(read_u16(message, pos + 4)?);
+    Ok((high << 32) | (mid << 16) | low)
+}
+
+fn encode_name(buffer: &mut Vec<u8>, name: &str) -> Result<()> {
+    let name = name.trim_end_matches('.');
+    let start = buffer.len();
+
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                bail!("Invalid label in domain name {}", name);
+            }
+            buffer.push(label.len() as u8);
+            buffer.extend_from_slice(label.as_bytes());
+        }
+    }
+    buffer.push(0);
+
+    if buffer.len() - start > 255 {
+        bail!("Domain name {} too long", name);
+    }
+    Ok(())
+}
+
+/// Reads a possibly compressed domain name and returns it together with the
+/// position following the name.
+fn read_name(message: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *message.get(pos).ok_or("Truncated dns message")? as usize;
+        match len {
+            0 => {
+                pos += 1;
+                break;
+            }
+            l if l & 0xC0 == 0xC0 => {
+                jumps += 1;
+                if jumps > 127 {
+                    bail!("Compression loop in dns message");
+                }
+                let pointer = read_u16(message, pos)? & 0x3FFF;
+                end = end.or(Some(pos + 2));
+                pos = pointer as usize;
+            }
+            l => {
+                let label = message
+                    .get(pos + 1..pos + 1 + l)
+                    .ok_or("Truncated dns message")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + l;
+            }
+        }
+    }
+
+    Ok((labels.join("."), end.unwrap_or(pos)))
+}
+
+fn skip_record(message: &[u8], pos: usize) -> Result<usize> {
+    let (_, pos) = read_name(message, pos)?;
+    let rdlength = read_u16(message, pos + 8)? as usize;
+    Ok(pos + 10 + rdlength)
+}
+
+struct TsigKey {
+    name: String,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    fn mac(
+        &self,
+        request_mac: Option<&[u8]>,
+        message: &[u8],
+        time_signed: u64,
+        error: u16,
+    ) -> Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret)
+            .map_err(|_| "Invalid TSIG secret")?;
+
+        if let Some(request_mac) = request_mac {
+            let mut length = Vec::with_capacity(2);
+            push_u16(&mut length, request_mac.len() as u16);
+            mac.input(&length);
+            mac.input(request_mac);
+        }
+
+        mac.input(message);
+
+        let mut variables = Vec::new();
+        encode_name(&mut variables, &self.name.to_lowercase())?;
+        push_u16(&mut variables, CLASS_ANY);
+        push_u32(&mut variables, 0);
+        encode_name(&mut variables, TSIG_ALGORITHM)?;
+        push_u48(&mut variables, time_signed);
+        push_u16(&mut variables, TSIG_FUDGE);
+        push_u16(&mut variables, error);
+        push_u16(&mut variables, 0);
+        mac.input(&variables);
+
+        Ok(mac)
+    }
+
+    /// Appends a TSIG record to `message` and returns the MAC.
+    fn sign(
+        &self,
+        message: &mut Vec<u8>,
+        request_mac: Option<&[u8]>,
+        time_signed: u64,
+    ) -> Result<Vec<u8>> {
+        let mac = self.mac(request_mac, message, time_signed, 0)?
+            .result()
+            .code()
+            .to_vec();
+
+        let id = read_u16(message, 0)?;
+        let additional = read_u16(message, 10)?;
+
+        let mut rdata = Vec::new();
+        encode_name(&mut rdata, TSIG_ALGORITHM)?;
+        push_u48(&mut rdata, time_signed);
+        push_u16(&mut rdata, TSIG_FUDGE);
+        push_u16(&mut rdata, mac.len() as u16);
+        rdata.extend_from_slice(&mac);
+        push_u16(&mut rdata, id);
+        push_u16(&mut rdata, 0);
+        push_u16(&mut rdata, 0);
+
+        encode_name(message, &self.name)?;
+        push_u16(message, TYPE_TSIG);
+        push_u16(message, CLASS_ANY);
+        push_u32(message, 0);
+        push_u16(message, rdata.len() as u16);
+        message.extend_from_slice(&rdata);
+
+        write_u16(message, 10, additional + 1);
+
+        Ok(mac)
+    }
+
+    /// Verifies the TSIG record of a response to a request signed with `request_mac`.
+    /// Responses signed outside of the fudge window around `now` are rejected as replays.
+    fn verify(&self, response: &[u8], request_mac: &[u8], now: u64) -> Result<()> {
+        let additional = read_u16(response, 10)?;
+        if additional == 0 {
+            bail!("Response is not signed");
+        }
+
+        let records = read_u16(response, 6)? as usize
+            + read_u16(response, 8)? as usize
+            + additional as usize - 1;
+        let mut pos = HEADER_SIZE;
+        for _ in 0..read_u16(response, 4)? {
+            let (_, end) = read_name(response, pos)?;
+            pos = end + 4;
+        }
+        for _ in 0..records {
+            pos = skip_record(response, pos)?;
+        }
+
+        let tsig_start = pos;
+        let (key_name, pos) = read_name(response, pos)?;
+        if read_u16(response, pos)? != TYPE_TSIG {
+            bail!("Last additional record is not a TSIG record");
+        }
+        if !key_name.eq_ignore_ascii_case(self.name.trim_end_matches('.')) {
+            bail!("Response signed with unknown key {}", key_name);
+        }
+
+        let (algorithm, pos) = read_name(response, pos + 10)?;
+        if !algorithm.eq_ignore_ascii_case(TSIG_ALGORITHM) {
+            bail!("Response signed with unexpected algorithm {}", algorithm);
+        }
+        let time_signed = read_u48(response, pos)?;
+        let fudge = read_u16(response, pos + 6)?;
+        let mac_size = read_u16(response, pos + 8)? as usize;
+        let mac = response
+            .get(pos + 10..pos + 10 + mac_size)
+            .ok_or("Truncated dns message")?;
+        let original_id = read_u16(response, pos + 10 + mac_size)?;
+        let error = read_u16(response, pos + 12 + mac_size)?;
+
+        if error != 0 {
+            bail!("Server rejected TSIG signature: {}", rcode_name(error));
+        }
+
+        let mut unsigned = response[..tsig_start].to_vec();
+        write_u16(&mut unsigned, 0, original_id);
+        write_u16(&mut unsigned, 10, additional - 1);
+
+        self.mac(Some(request_mac), &unsigned, time_signed, error)?
+            .verify(mac)
+            .map_err(|_| "Invalid TSIG signature in response")?;
+
+        // checked after the signature like RFC 8945 requires, the time is only
+        // trustworthy if the signature is valid
+        let offset = if now > time_signed {
+            now - time_signed
+        } else {
+            time_signed - now
+        };
+        if offset > u64::from(fudge) {
+            bail!("Response signed {} seconds off the current time: {}", offset, rcode_name(18));
+        }
+
+        Ok(())
+    }
+}
+
+/// Random message id, so responses can't be forged without seeing the request.
+fn message_id() -> Result<u16> {
+    use openssl::rand::rand_bytes;
+
+    let mut id = [0; 2];
+    rand_bytes(&mut id).chain_err(|| "Error generating message id")?;
+    Ok(u16::from(id[0]) << 8 | u16::from(id[1]))
+}
+
+fn build_update(id: u16, zone: &str, name: &str, ttl: u32, addr: &IpAddr) -> Result<Vec<u8>> {
+    let (record_type, rdata) = match *addr {
+        IpAddr::V4(addr) => (TYPE_A, addr.octets().to_vec()),
+        IpAddr::V6(addr) => (TYPE_AAAA, addr.octets().to_vec()),
+    };
+
+    let mut message = Vec::with_capacity(MAX_UDP_SIZE);
+
+    push_u16(&mut message, id);
+    push_u16(&mut message, OPCODE_UPDATE << 11);
+    push_u16(&mut message, 1); // zone
+    push_u16(&mut message, 0); // prerequisites
+    push_u16(&mut message, 2); // updates
+    push_u16(&mut message, 0); // additional
+
+    encode_name(&mut message, zone)?;
+    push_u16(&mut message, TYPE_SOA);
+    push_u16(&mut message, CLASS_IN);
+
+    // delete the existing RRset of the name
+    encode_name(&mut message, name)?;
+    push_u16(&mut message, record_type);
+    push_u16(&mut message, CLASS_ANY);
+    push_u32(&mut message, 0);
+    push_u16(&mut message, 0);
+
+    // add the new record
+    encode_name(&mut message, name)?;
+    push_u16(&mut message, record_type);
+    push_u16(&mut message, CLASS_IN);
+    push_u32(&mut message, ttl);
+    push_u16(&mut message, rdata.len() as u16);
+    message.extend_from_slice(&rdata);
+
+    Ok(message)
+}
+
+pub struct Rfc2136Client {
+    logger: Logger,
+    server: SocketAddr,
+    zone: String,
+    name: String,
+    ttl: u32,
+    key: TsigKey,
+}
+
+impl Rfc2136Client {
+    pub fn new(parent_logger: &Logger, domain: &str, config: &Rfc2136Config) -> Result<Self> {
+        use std::net::ToSocketAddrs;
+
+        let logger = parent_logger.new(o!("dns-service" => "rfc2136"));
+
+        let server = config
+            .server
+            .to_socket_addrs()
+            .or_else(|_| (config.server.as_str(), 53).to_socket_addrs())
+            .chain_err(|| format!("Error resolving {}", config.server))?
+            .next()
+            .ok_or_else(|| format!("No address found for {}", config.server))?;
+
+        let zone = config.zone.as_ref().map_or(domain, String::as_str);
+        let zone = zone.trim_end_matches('.').to_lowercase();
+        let name = domain.trim_end_matches('.').to_lowercase();
+        if name != zone && !name.ends_with(&format!(".{}", zone)) {
+            bail!("Domain {} is not part of zone {}", name, zone);
+        }
+
+        let secret = ::base64::decode(&config.tsig_secret)
+            .chain_err(|| "Error decoding TSIG secret")?;
+
+        Ok(Rfc2136Client {
+            logger,
+            server,
+            zone,
+            name,
+            ttl: config.ttl,
+            key: TsigKey {
+                name: config.tsig_key_name.clone(),
+                secret,
+            },
+        })
+    }
+
+    fn exchange_udp(&self, request: &[u8], id: u16) -> Result<Vec<u8>> {
+        use std::net::UdpSocket;
+
+        let local: SocketAddr = match self.server {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).chain_err(|| "Error binding udp socket")?;
+        socket
+            .set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))
+            .chain_err(|| "Error setting timeout")?;
+        socket
+            .connect(self.server)
+            .chain_err(|| "Error connecting udp socket")?;
+        socket.send(request).chain_err(|| "Error sending update")?;
+
+        let mut buffer = vec![0; 4096];
+        loop {
+            let len = socket
+                .recv(&mut buffer)
+                .chain_err(|| "Error receiving response")?;
+            if len >= HEADER_SIZE && read_u16(&buffer, 0)? == id {
+                buffer.truncate(len);
+                return Ok(buffer);
+            }
+            debug!(self.logger, "ignoring unrelated datagram");
+        }
+    }
+
+    fn exchange_tcp(&self, request: &[u8]) -> Result<Vec<u8>> {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        let timeout = Duration::from_secs(TIMEOUT_SECS);
+        let mut stream = TcpStream::connect_timeout(&self.server, timeout)
+            .chain_err(|| "Error connecting to server")?;
+        stream
+            .set_read_timeout(Some(timeout))
+            .chain_err(|| "Error setting timeout")?;
+
+        let mut framed = Vec::with_capacity(request.len() + 2);
+        push_u16(&mut framed, request.len() as u16);
+        framed.extend_from_slice(request);
+        stream.write_all(&framed).chain_err(|| "Error sending update")?;
+
+        let mut length = [0; 2];
+        stream
+            .read_exact(&mut length)
+            .chain_err(|| "Error receiving response")?;
+        let mut response = vec![0; read_u16(&length, 0)? as usize];
+        stream
+            .read_exact(&mut response)
+            .chain_err(|| "Error receiving response")?;
+
+        Ok(response)
+    }
+}
+
+impl DnsService for Rfc2136Client {
+    fn update(&self, addr: &IpAddr) -> Result<Update> {
+        use chrono::Utc;
+
+        info!(self.logger, "called with: {}", addr);
+
+        let id = message_id()?;
+        let mut request = build_update(id, &self.zone, &self.name, self.ttl, addr)?;
+        let request_mac = self.key
+            .sign(&mut request, None, Utc::now().timestamp() as u64)
+            .chain_err(|| "Error signing update")?;
+
+        let mut response = if request.len() <= MAX_UDP_SIZE {
+            self.exchange_udp(&request, id)?
+        } else {
+            self.exchange_tcp(&request)?
+        };
+
+        if read_u16(&response, 2)? & FLAG_TC != 0 {
+            debug!(self.logger, "response truncated, retrying over tcp");
+            response = self.exchange_tcp(&request)?;
+        }
+
+        let flags = read_u16(&response, 2)?;
+        if read_u16(&response, 0)? != id || flags & FLAG_QR == 0 {
+            bail!("Unexpected response from server");
+        }
+
+        let rcode = flags & 0x000F;
+        if rcode != 0 {
+            bail!("Server refused update: {}", rcode_name(rcode));
+        }
+
+        self.key
+            .verify(&response, &request_mac, Utc::now().timestamp() as u64)
+            .chain_err(|| "Error verifying response")?;
+
+        Ok(Update::Published)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Rfc2136Config;
+    use slog;
+    use std::net::UdpSocket;
+    use std::thread;
+
+    fn key() -> TsigKey {
+        TsigKey {
+            name: "update-key.".to_owned(),
+            secret: b"0123456789abcdef".to_vec(),
+        }
+    }
+
+    /// Answers a single update with the given rcode, signed like a server would.
+    fn mock_server(rcode: u16) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buffer = vec![0; 4096];
+            let (len, peer) = socket.recv_from(&mut buffer).unwrap();
+            let request = buffer[..len].to_vec();
+
+            let (_, pos) = read_name(&request, HEADER_SIZE).unwrap();
+            let mut response = request[..pos + 4].to_vec();
+            write_u16(&mut response, 2, FLAG_QR | (OPCODE_UPDATE << 11) | rcode);
+            response[6..12].copy_from_slice(&[0; 6]);
+
+            let request_mac = extract_mac(&request);
+            let now = ::chrono::Utc::now().timestamp() as u64;
+            key().sign(&mut response, Some(&request_mac), now).unwrap();
+            socket.send_to(&response, peer).unwrap();
+
+            request
+        });
+
+        (addr, handle)
+    }
+
+    fn extract_mac(message: &[u8]) -> Vec<u8> {
+        let mut pos = HEADER_SIZE;
+        let (_, end) = read_name(message, pos).unwrap();
+        pos = end + 4;
+        for _ in 0..read_u16(message, 8).unwrap() {
+            pos = skip_record(message, pos).unwrap();
+        }
+        let (_, pos) = read_name(message, pos).unwrap();
+        let (_, pos) = read_name(message, pos + 10).unwrap();
+        let size = read_u16(message, pos + 8).unwrap() as usize;
+        message[pos + 10..pos + 10 + size].to_vec()
+    }
+
+    fn client(server: SocketAddr) -> Rfc2136Client {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let config = Rfc2136Config {
+            server: server.to_string(),
+            zone: Some("example.com".to_owned()),
+            ttl: 60,
+            tsig_key_name: "update-key.".to_owned(),
+            tsig_secret: ::base64::encode(b"0123456789abcdef"),
+        };
+
+        Rfc2136Client::new(&logger, "home.example.com", &config).unwrap()
+    }
+
+    #[test]
+    fn names_are_encoded_as_labels() {
+        let mut buffer = Vec::new();
+        encode_name(&mut buffer, "home.example.com.").unwrap();
+        assert_eq!(buffer, b"\x04home\x07example\x03com\x00".to_vec());
+
+        let (name, end) = read_name(&buffer, 0).unwrap();
+        assert_eq!(name, "home.example.com");
+        assert_eq!(end, buffer.len());
+
+        assert!(encode_name(&mut Vec::new(), "a..b").is_err());
+    }
+
+    #[test]
+    fn compressed_names_are_read() {
+        let message = b"\x07example\x03com\x00\x04home\xC0\x00";
+        let (name, end) = read_name(message, 13).unwrap();
+        assert_eq!(name, "home.example.com");
+        assert_eq!(end, message.len());
+    }
+
+    #[test]
+    fn signed_messages_verify() {
+        let addr = "192.0.2.1".parse().unwrap();
+        let mut request = build_update(7, "example.com", "example.com", 60, &addr).unwrap();
+        let request_mac = key().sign(&mut request, None, 1000).unwrap();
+        assert_eq!(read_u16(&request, 10).unwrap(), 1);
+
+        let mut response = request[..HEADER_SIZE].to_vec();
+        response[4..12].copy_from_slice(&[0; 8]);
+        key().sign(&mut response, Some(&request_mac), 1000).unwrap();
+        assert!(key().verify(&response, &request_mac, 1000).is_ok());
+        assert!(key().verify(&response, &request_mac, 1300).is_ok());
+        assert!(key().verify(&response, &request_mac, 700).is_ok());
+
+        // a replayed response is outside of the fudge window
+        assert!(key().verify(&response, &request_mac, 1301).is_err());
+        assert!(key().verify(&response, &request_mac, 699).is_err());
+
+        let last = response.len() - 8;
+        response[last] ^= 1;
+        assert!(key().verify(&response, &request_mac, 1000).is_err());
+    }
+
+    #[test]
+    fn update_replaces_record() {
+        let (server, handle) = mock_server(0);
+
+        client(server).update(&"2001:db8::1".parse().unwrap()).unwrap();
+
+        let request = handle.join().unwrap();
+        assert_eq!(read_u16(&request, 2).unwrap(), OPCODE_UPDATE << 11);
+        assert_eq!(read_u16(&request, 8).unwrap(), 2);
+
+        let (zone, pos) = read_name(&request, HEADER_SIZE).unwrap();
+        assert_eq!(zone, "example.com");
+
+        let (name, pos) = read_name(&request, pos + 4).unwrap();
+        assert_eq!(name, "home.example.com");
+        assert_eq!(read_u16(&request, pos).unwrap(), TYPE_AAAA);
+        assert_eq!(read_u16(&request, pos + 2).unwrap(), CLASS_ANY);
+    }
+
+    #[test]
+    fn refused_update_is_error() {
+        let (server, _) = mock_server(5);
+
+        let result = client(server).update(&"192.0.2.1".parse().unwrap());
+
+        assert!(result.is_err());
+    }
+}
@@ -1,81 +1,419 @@
 use config;
-use dns::DnsService;
+use dns::{DnsService, Update};
+use dyndns2::{is_fqdn, same_host, ReturnCode, MAX_HOSTNAMES};
 use errors::*;
-use hyper::{Request, Response, StatusCode};
+use futures_cpupool::CpuPool;
+use futures::{future, Future, Stream};
+use hyper::{Body, Request, Response, StatusCode};
+use lockout::Lockout;
+use metrics::Metrics;
+use policy::is_acceptable;
+use proxy::{canonical, client_addr, hops, is_trusted};
 use slog::Logger;
+use state::StateStore;
+use status::{DomainStatus, LastErrors};
+use systemd::Notifier;
 use std::borrow::Cow;
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tls::ReloadableAcceptor;
+use tokio::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::TlsStream;
 
-fn authenticate<R>(config: &config::Config, r: &Request<R>) -> bool {
-    match r.headers().get(::hyper::header::AUTHORIZATION) {
-        Some(scheme) => {
-            let token = match scheme.to_str() {
-                Ok(t) => t,
-                Err(_) => return false,
-            };
-            let mut expected = String::from("Basic ");
-            expected += &::base64::encode(&format!(
-                "{}:{}",
-                &config.http_auth_user, &config.http_auth_password
-            ));
-            compare_secure(&expected, token)
+/// Connections which haven't completed the TLS handshake yet.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+/// Pause after failing to accept a connection, e.g. because of too many open files.
+const ACCEPT_ERROR_DELAY_MILLIS: u64 = 100;
+
+/// Returns the user matching the HTTP Basic credentials of the request.
+fn authenticate<'a, R>(config: &'a config::Config, r: &Request<R>) -> Option<&'a config::UserConfig> {
+    static SCHEME: &str = "Basic ";
+
+    let token = r.headers()
+        .get(::hyper::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    if !token.starts_with(SCHEME) {
+        return None;
+    }
+
+    let credentials = ::base64::decode(&token[SCHEME.len()..]).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let mut credentials = credentials.splitn(2, ':');
+    let (name, password) = (credentials.next()?, credentials.next()?);
+
+    match config.users.iter().find(|user| user.name == name) {
+        Some(user) if user.password.verify(password) => Some(user),
+        Some(_) => None,
+        None => {
+            // costs as much as checking the slowest known user, so the response
+            // time doesn't reveal which names exist
+            slowest_password(config)?.verify_dummy(password);
+            None
         }
-        None => false,
     }
 }
 
-#[inline(never)]
-fn compare_secure(s1: &str, s2: &str) -> bool {
-    use consistenttime::ct_u8_slice_eq;
-    ct_u8_slice_eq(s1.as_bytes(), s2.as_bytes())
+fn slowest_password(config: &config::Config) -> Option<&::password::Password> {
+    config
+        .users
+        .iter()
+        .map(|user| &user.password)
+        .max_by_key(|password| password.cost())
 }
 
-fn handle_request<R, Service>(
-    req: Request<R>,
+enum Login<'a> {
+    User(&'a config::UserConfig),
+    Failed,
+    LockedOut,
+}
+
+/// Authenticates the request, unless its client is locked out after too many failures.
+fn login<'a, R, Service>(
+    context: &'a Context<Service>,
+    req: &Request<R>,
+    peer: &IpAddr,
     logger: &Logger,
-    config: &config::Config,
-    service: &Service,
-) -> ::http::Result<Response<::hyper::Body>>
+) -> Login<'a> {
+    use chrono::Utc;
+
+    let client = source_addr(&context.config, req, peer);
+    let now = Utc::now().timestamp();
+
+    if let Some(until) = context.lockout.locked_until(&client, now) {
+        info!(logger, "rejected locked out client";
+            "client" => format!("{}", client),
+            "remaining" => until - now);
+        return Login::LockedOut;
+    }
+
+    if let Some(user) = authenticate(&context.config, req) {
+        context.lockout.success(&client);
+        return Login::User(user);
+    }
+
+    context.metrics.auth_failure();
+
+    // clients commonly try without credentials first, that's no attack
+    if req.headers().contains_key(::hyper::header::AUTHORIZATION) {
+        if let Some(until) = context.lockout.failure(&client, now) {
+            warn!(logger, "locked out client after too many failed logins";
+                "client" => format!("{}", client),
+                "duration" => until - now);
+        }
+    }
+
+    Login::Failed
+}
+
+/// The address failed logins are counted for: the peer, or the client behind a trusted proxy.
+fn source_addr<R>(config: &config::Config, req: &Request<R>, peer: &IpAddr) -> IpAddr {
+    if let config::IpResolvMethod::Header(ref header_name) = config.ip_resolv {
+        if is_trusted(peer, &config.trusted_proxies) {
+            if let Ok(hops) = hops(req.headers(), header_name) {
+                if let Ok(addr) = client_addr(&hops, &config.trusted_proxies) {
+                    return addr;
+                }
+            }
+        }
+    }
+    *peer
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = ::http::Error> + Send>;
+
+struct Context<Service> {
+    logger: Logger,
+    config: Arc<config::Config>,
+    state: Arc<StateStore>,
+    services: BTreeMap<String, Service>,
+    pool: CpuPool,
+    errors: Arc<LastErrors>,
+    metrics: Arc<Metrics>,
+    lockout: Lockout,
+    notifier: Arc<Notifier>,
+}
+
+/// Answers with one line per return code.
+fn respond(metrics: &Metrics, codes: &[ReturnCode]) -> ResponseFuture {
+    metrics.requests(codes);
+
+    let mut response = Response::builder();
+    response.status(StatusCode::OK);
+
+    if codes.contains(&ReturnCode::BadAuth) {
+        response.header(::hyper::header::WWW_AUTHENTICATE, "Basic");
+    }
+
+    let body = codes
+        .iter()
+        .map(ReturnCode::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Box::new(future::result(response.body(body.into())))
+}
+
+/// Dispatches requests to the health check, the status page, the metrics and the update handler.
+fn route<R, Service>(
+    req: Request<R>,
+    peer: SocketAddr,
+    context: &Arc<Context<Service>>,
+) -> ResponseFuture
+where
+    Service: DnsService + Send + Sync + 'static,
+{
+    match req.uri().path() {
+        "/healthz" => Box::new(future::result(
+            Response::builder().status(StatusCode::OK).body("ok".into()),
+        )),
+        "/status" => handle_status(req, peer, context),
+        "/metrics" => Box::new(future::result(
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(::hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(context.metrics.render().into()),
+        )),
+        _ => handle_request(req, peer, context),
+    }
+}
+
+/// Answers with the state of the domains the user may update.
+fn handle_status<R, Service>(
+    req: Request<R>,
+    peer: SocketAddr,
+    context: &Arc<Context<Service>>,
+) -> ResponseFuture
+where
+    Service: DnsService + Send + Sync + 'static,
+{
+    // verifying the password takes a while, the checks sign a message and
+    // connect to the SMTP server, so neither may block the event loop
+    let (parts, _) = req.into_parts();
+    let req = Request::from_parts(parts, ());
+    let context = context.clone();
+    Box::new(context.clone().pool.spawn_fn(move || {
+        let user = match login(&context, &req, &canonical(peer.ip()), &context.logger) {
+            Login::User(user) => user,
+            Login::Failed => {
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(::hyper::header::WWW_AUTHENTICATE, "Basic")
+                    .body(Body::empty())
+            }
+            Login::LockedOut => {
+                return Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .body(Body::empty())
+            }
+        };
+
+        match domain_status(&context, user) {
+            Ok(status) => Response::builder()
+                .status(StatusCode::OK)
+                .header(::hyper::header::CONTENT_TYPE, "application/json")
+                .body(status.into()),
+            Err(e) => {
+                log_error(&context.logger, &e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+            }
+        }
+    }))
+}
+
+fn domain_status<Service>(context: &Context<Service>, user: &config::UserConfig) -> Result<String>
 where
     Service: DnsService,
 {
-    let logger = logger.new(o!(
+    let mut domains = BTreeMap::new();
+    for domain in &context.config.domains {
+        if !user.may_update(&domain.name) || !domain.allows(&user.name) {
+            continue;
+        }
+
+        let checks = context
+            .services
+            .get(&domain.name)
+            .map_or_else(Vec::new, DnsService::check);
+        let status = DomainStatus::new(
+            &context.state.get(&domain.name)?,
+            context.errors.get(&domain.name),
+            checks,
+        );
+        domains.insert(domain.name.clone(), status);
+    }
+
+    ::serde_json::to_string_pretty(&domains).chain_err(|| "Error serializing status")
+}
+
+fn handle_request<R, Service>(
+    req: Request<R>,
+    peer: SocketAddr,
+    context: &Arc<Context<Service>>,
+) -> ResponseFuture
+where
+    Service: DnsService + Send + Sync + 'static,
+{
+    let logger = context.logger.new(o!(
         "url" => format!("{}", req.uri())
     ));
     debug!(logger, "{:?}", req.headers());
 
-    if !authenticate(&config, &req) {
-        return Response::builder()
-            .status(StatusCode::OK)
-            .header(::hyper::header::WWW_AUTHENTICATE, "Basic")
-            .body("badauth".into());
+    // verifying the password and updating (gpg, smtp, ...) take a while,
+    // so neither may block the event loop
+    let (parts, _) = req.into_parts();
+    let req = Request::from_parts(parts, ());
+    let peer = canonical(peer.ip());
+    let metrics = context.metrics.clone();
+    let context = context.clone();
+    let codes = context.clone().pool.spawn_fn(move || -> ::std::result::Result<_, ()> {
+        Ok(process_request(&context, &req, &peer, &logger))
+    });
+
+    Box::new(codes.then(move |codes| {
+        respond(&metrics, &codes.unwrap_or_else(|_| vec![ReturnCode::ServerError]))
+    }))
+}
+
+/// Logs in and updates the requested hostnames, answering one return code per hostname.
+fn process_request<Service>(
+    context: &Context<Service>,
+    req: &Request<()>,
+    peer: &IpAddr,
+    logger: &Logger,
+) -> Vec<ReturnCode>
+where
+    Service: DnsService,
+{
+    let config = &context.config;
+
+    let user = match login(context, req, peer, logger) {
+        Login::User(user) => user,
+        Login::Failed => return vec![ReturnCode::BadAuth],
+        Login::LockedOut => return vec![ReturnCode::Abuse],
+    };
+    let logger = logger.new(o!("user" => user.name.clone()));
+
+    let has_agent = req.headers()
+        .get(::hyper::header::USER_AGENT)
+        .map_or(false, |agent| !agent.is_empty());
+    if !has_agent {
+        info!(logger, "request without user agent");
+        return vec![ReturnCode::BadAgent];
     }
 
-    use std::net::Ipv4Addr;
-    use std::str::FromStr;
+    let hostnames = match hostnames_from_request(config, req) {
+        Ok(hostnames) => hostnames,
+        Err(code) => {
+            info!(logger, "invalid hostnames: {}", code);
+            return vec![code];
+        }
+    };
 
-    let ip = match resolv_ip_from_request(config, &req) {
+    let ip = match resolv_ip_from_request(config, req, peer, &logger) {
         Some(ip) => ip,
-        None => {
-            return Response::builder()
-                .status(StatusCode::OK)
-                .body("badrequest".into());
+        None if config.ip_peer_fallback => {
+            debug!(logger, "no address in request, using peer address {}", peer);
+            Ok(*peer)
         }
+        None => return vec![ReturnCode::BadRequest],
     };
 
-    let result = ip.and_then(|s| {
-        Ipv4Addr::from_str(&s).chain_err(|| "Error interpreting address as ipv4")
-    }).and_then(|ip| service.update(&ip));
+    let ip = match ip {
+        Ok(ip) => ip,
+        Err(e) => {
+            log_error(&logger, &e);
+            return vec![ReturnCode::BadRequest];
+        }
+    };
 
-    if let Err(e) = result {
-        log_error(&logger, &e);
+    if !is_acceptable(&ip, &config.denied_networks, &user.allowed_networks) {
+        warn!(logger, "address {} rejected by policy", ip);
+        return vec![ReturnCode::BadAddr(ip)];
+    }
+
+    hostnames
+        .iter()
+        .map(|hostname| match find_domain(config, hostname, user) {
+            Ok(domain) => {
+                let code = update_host(context, &logger, domain, &ip);
+                context.metrics.update(&domain.name, &code);
+                context.notifier.status(&format!("last update: {} {}", domain.name, code));
+                code
+            }
+            Err(code) => {
+                info!(logger, "invalid hostname {}: {}", hostname, code);
+                code
+            }
+        })
+        .collect()
+}
 
-        return Response::builder()
-            .status(StatusCode::OK)
-            .body("dnserr".into());
+fn update_host<Service>(
+    context: &Context<Service>,
+    logger: &Logger,
+    domain: &config::DomainConfig,
+    ip: &IpAddr,
+) -> ReturnCode
+where
+    Service: DnsService,
+{
+    let config = &context.config;
+    let logger = logger.new(o!("domain" => domain.name.clone()));
+
+    let service = match context.services.get(&domain.name) {
+        Some(service) => service,
+        None => {
+            error!(logger, "no dns service for domain");
+            return ReturnCode::ServerError;
+        }
+    };
+
+    let published = match context.state.get(&domain.name) {
+        Ok(published) => published,
+        Err(e) => {
+            log_error(&logger, &e);
+            return ReturnCode::ServerError;
+        }
     };
 
-    Response::builder().status(StatusCode::OK).body("good".into())
+    if published.address(ip) == Some(*ip) {
+        info!(logger, "address {} unchanged, skipping update", ip);
+        return ReturnCode::NoChg(*ip);
+    }
+
+    // each family has its own interval, dual-stack clients update both in a row
+    if let Some(updated) = published.updated_at(ip) {
+        use chrono::Utc;
+
+        let elapsed = Utc::now().timestamp() - updated;
+        if elapsed < config.min_update_interval as i64 {
+            warn!(logger, "update {} seconds after the previous one rejected", elapsed);
+            return ReturnCode::Abuse;
+        }
+    }
+
+    match service.update(ip) {
+        Ok(Update::Published) => if let Err(e) = context.state.record(&domain.name, ip) {
+            log_error(&logger, &e);
+        },
+        // recorded by the service once delivered, the client need not send it again
+        Ok(Update::Queued) => info!(logger, "update to {} queued", ip),
+        Err(e) => {
+            log_error(&logger, &e);
+            context.errors.record(&domain.name, &e);
+            return ReturnCode::DnsErr;
+        }
+    }
+
+    ReturnCode::Good(*ip)
 }
 
 fn find_in_query<'a, 'b>(query: &'a str, name: &'b str) -> Option<Cow<'a, str>> {
@@ -86,69 +424,853 @@ fn find_in_query<'a, 'b>(query: &'a str, name: &'b str) -> Option<Cow<'a, str>>
         .map(|(_, val)| val)
 }
 
+/// Reads the comma separated `hostname` parameter, which is mandatory for `DynDns2`.
+/// For the other resolution methods it defaults to the managed domain, if there is only one.
+fn hostnames_from_request<R>(
+    c: &config::Config,
+    req: &Request<R>,
+) -> ::std::result::Result<Vec<String>, ReturnCode> {
+    static DOMAIN_HEADER: &str = "hostname";
+
+    let hostnames = req.uri()
+        .query()
+        .and_then(|query| find_in_query(query, DOMAIN_HEADER));
+
+    let hostnames: Vec<String> = match (hostnames, &c.ip_resolv) {
+        (Some(hostnames), _) => hostnames.split(',').map(|h| h.trim().to_owned()).collect(),
+        (None, &config::IpResolvMethod::DynDns2) => return Err(ReturnCode::NotFqdn),
+        (None, _) if c.domains.len() == 1 => vec![c.domains[0].name.clone()],
+        (None, _) => return Err(ReturnCode::NotFqdn),
+    };
+
+    if hostnames.len() > MAX_HOSTNAMES {
+        return Err(ReturnCode::NumHost);
+    }
+
+    Ok(hostnames)
+}
+
+/// Looks up the domain entry of `hostname`, provided `user` may update it.
+fn find_domain<'a>(
+    c: &'a config::Config,
+    hostname: &str,
+    user: &config::UserConfig,
+) -> ::std::result::Result<&'a config::DomainConfig, ReturnCode> {
+    if !is_fqdn(hostname) {
+        return Err(ReturnCode::NotFqdn);
+    }
+
+    if !user.may_update(hostname) {
+        return Err(ReturnCode::NoHost);
+    }
+
+    c.domains
+        .iter()
+        .find(|domain| same_host(hostname, &domain.name) && domain.allows(&user.name))
+        .ok_or(ReturnCode::NoHost)
+}
+
 fn resolv_ip_from_request<R>(
     c: &config::Config,
     req: &Request<R>,
-) -> Option<Result<String>> {
+    peer: &IpAddr,
+    logger: &Logger,
+) -> Option<Result<IpAddr>> {
+    use std::str::FromStr;
+
     match &c.ip_resolv {
         config::IpResolvMethod::DynDns2 => {
-            static DOMAIN_HEADER: &str = "hostname";
             static IP_HEADER: &str = "myip";
 
             let query = req.uri().query()?;
-            let domain = find_in_query(query, DOMAIN_HEADER)?;
-
-            if domain != c.domain {
+            let ip = find_in_query(query, IP_HEADER)?;
+            Some(IpAddr::from_str(&ip).chain_err(|| "Error interpreting address as ipv4 or ipv6"))
+        }
+        config::IpResolvMethod::Header(header_name) => {
+            let trusted = &c.trusted_proxies;
+            if !is_trusted(peer, trusted) {
+                warn!(logger, "ignoring {} header of untrusted peer {}", header_name, peer);
                 return None;
             }
 
-            let ip = find_in_query(query, IP_HEADER)?;
-            Some(Ok(ip.to_string()))
-        }
-        config::IpResolvMethod::Header(header_name) => match req.headers().get(header_name) {
-            Some(value) => Some(
-                value
-                    .to_str()
-                    .chain_err(|| "Invalid value for ip header")
-                    .map(String::from),
-            ),
-            None => None,
-        },
+            match hops(req.headers(), header_name) {
+                Ok(ref hops) if hops.is_empty() => None,
+                Ok(hops) => Some(client_addr(&hops, trusted)),
+                Err(e) => Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Accepts connections on `listener`. Failing to accept a connection, e.g. because
+/// of too many open files, pauses accepting instead of stopping the server.
+fn accept(
+    listener: ::std::net::TcpListener,
+    logger: Logger,
+) -> Result<impl Stream<Item = TcpStream, Error = io::Error> + Send> {
+    use futures::future::Either;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+    use tokio::reactor::Handle;
+    use tokio_timer::Delay;
+
+    listener
+        .set_nonblocking(true)
+        .chain_err(|| "Error configuring listening socket")?;
+    let listener = TcpListener::from_std(listener, &Handle::default())
+        .chain_err(|| "Error registering listening socket")?;
+
+    Ok(listener
+        .incoming()
+        .then(move |result| match result {
+            Ok(stream) => Either::A(future::ok(Some(stream))),
+            Err(e) => {
+                log_error(&logger, &Error::with_chain(e, "Error accepting connection"));
+                let delay = Duration::from_millis(ACCEPT_ERROR_DELAY_MILLIS);
+                Either::B(Delay::new(Instant::now() + delay).then(|_| Ok(None)))
+            }
+        })
+        .filter_map(|stream| stream))
+}
+
+/// Performs the TLS handshakes of incoming connections. A failed handshake only
+/// drops the affected connection.
+fn tls_incoming<I>(
+    incoming: I,
+    acceptor: ReloadableAcceptor,
+    logger: Logger,
+) -> impl Stream<Item = TlsStream<TcpStream>, Error = io::Error> + Send
+where
+    I: Stream<Item = TcpStream, Error = io::Error> + Send,
+{
+    use tokio_timer::Timeout;
+
+    incoming
+        .map(move |stream| {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_owned(), |peer| peer.to_string());
+            let logger = logger.new(o!("peer" => peer));
+
+            future::result(acceptor.current())
+                .and_then(|acceptor| {
+                    Timeout::new(acceptor.accept(stream), Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))
+                        .map_err(|e| match e.into_inner() {
+                            Some(e) => Error::with_chain(e, "TLS handshake failed"),
+                            None => "TLS handshake timed out".into(),
+                        })
+                })
+                .then(move |result| match result {
+                    Ok(stream) => Ok(Some(stream)),
+                    Err(e) => {
+                        log_error(&logger, &e);
+                        Ok(None)
+                    }
+                })
+        })
+        .buffer_unordered(MAX_PENDING_HANDSHAKES)
+        .filter_map(|stream| stream)
+}
+
+/// Connections which know the address of their client.
+trait PeerAddr {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl<S: PeerAddr> PeerAddr for TlsStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().get_ref().peer_addr()
     }
 }
 
+/// Serves `incoming` until `shutdown` resolves and the open connections are finished.
+fn serve<I, Service, F>(
+    incoming: I,
+    context: Arc<Context<Service>>,
+    shutdown: F,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    I: Stream<Error = io::Error> + Send + 'static,
+    I::Item: AsyncRead + AsyncWrite + PeerAddr + Send + 'static,
+    Service: DnsService + Send + Sync + 'static,
+    F: Future<Item = ()> + Send + 'static,
+{
+    use hyper::server::Server;
+    use hyper::service::{make_service_fn, service_fn};
+
+    // a connection whose peer is already gone is dropped
+    let make_service = make_service_fn(move |conn: &I::Item| {
+        let context = context.clone();
+        future::result(conn.peer_addr().map(|peer| {
+            service_fn(move |req: Request<Body>| route(req, peer, &context))
+        }))
+    });
+
+    Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .map_err(|e| {
+            eprintln!("server error: {}", e);
+        })
+}
+
+/// Reloads certificate and key whenever the process receives SIGHUP.
+fn reload_on_sighup(
+    acceptor: ReloadableAcceptor,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> + Send {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    let error_logger = logger.clone();
+
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .for_each(move |_| {
+            match acceptor.reload() {
+                Ok(()) => info!(logger, "reloaded TLS certificate"),
+                Err(e) => {
+                    log_error(&logger, &e);
+                    warn!(logger, "keeping previous TLS certificate");
+                }
+            }
+            Ok(())
+        })
+        .map_err(move |e| {
+            log_error(&error_logger, &Error::with_chain(e, "Error listening for SIGHUP"));
+        })
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+fn shutdown_signal(logger: Logger) -> impl Future<Item = (), Error = ()> + Send {
+    use futures::future::Either;
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    Signal::new(SIGTERM)
+        .flatten_stream()
+        .select(Signal::new(SIGINT).flatten_stream())
+        .into_future()
+        .then(move |result| match result {
+            Ok((Some(signal), _)) => {
+                info!(logger, "received signal {}, shutting down", signal);
+                Either::A(future::ok(()))
+            }
+            Ok((None, _)) => Either::B(future::empty()),
+            Err((e, _)) => {
+                // without signal handling the server is simply killed, as before
+                log_error(&logger, &Error::with_chain(e, "Error listening for SIGTERM and SIGINT"));
+                Either::B(future::empty())
+            }
+        })
+}
+
+/// Serves requests until SIGTERM or SIGINT. Returns the shutdown deadline, which
+/// the remaining cleanup shares with the pending requests.
 pub fn run_server<Service>(
     logger: &Logger,
-    service: Service,
+    services: BTreeMap<String, Service>,
     config: Arc<config::Config>,
-) -> Result<()>
+    state: Arc<StateStore>,
+    metrics: Arc<Metrics>,
+    errors: Arc<LastErrors>,
+) -> Result<Instant>
 where
     Service: DnsService + Send + Sync + 'static,
 {
-    use hyper::server::Server;
-    let logger = Arc::new(logger.new(o!("component" => "iron-server")));
-    let service = Arc::new(service);
-    use hyper::rt::Future;
+    let logger = logger.new(o!("component" => "iron-server"));
 
-    let addr = config
-        .server_addr
-        .parse()
-        .chain_err(|| "Error parsing server address")?;
+    let listener = match ::systemd::listen_fds().chain_err(|| "Error taking over socket from systemd")? {
+        Some(listener) => {
+            info!(logger, "using socket passed by systemd");
+            listener
+        }
+        None => {
+            let addr: SocketAddr = config
+                .server_addr
+                .parse()
+                .chain_err(|| "Error parsing server address")?;
+            ::std::net::TcpListener::bind(addr).chain_err(|| "Error binding server address")?
+        }
+    };
 
-    let new_service = move || {
-        let logger = logger.clone();
-        let config = config.clone();
-        let service = service.clone();
-        ::hyper::service::service_fn_ok(move |req: Request<::hyper::Body>| {
-            handle_request(req, &logger.clone(), &config.clone(), &*service).unwrap()
+    let notifier = Arc::new(Notifier::from_env().chain_err(|| "Error connecting to systemd")?);
+
+    let acceptor = match config.tls {
+        Some(ref tls) => Some(
+            ReloadableAcceptor::new(tls).chain_err(|| "Error loading TLS certificate")?,
+        ),
+        None => None,
+    };
+
+    let pool = ::futures_cpupool::Builder::new()
+        .pool_size(config.worker_threads)
+        .name_prefix("update-worker-")
+        .create();
+
+    let lockout = Lockout::new(config.auth_max_failures, config.auth_failure_window);
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
+
+    let context = Arc::new(Context {
+        logger: logger.clone(),
+        config,
+        state,
+        services,
+        pool,
+        errors,
+        metrics,
+        lockout,
+        notifier: notifier.clone(),
+    });
+
+    let incoming = accept(listener, logger.clone())?;
+
+    let mut runtime = ::tokio::runtime::Runtime::new().chain_err(|| "Error creating runtime")?;
+
+    // stops accepting connections, pending requests are answered until the deadline
+    let deadline_at = Arc::new(Mutex::new(None));
+    let shutdown = shutdown_signal(logger.clone()).shared();
+    let stop_accepting = {
+        let notifier = notifier.clone();
+        shutdown.clone().then(move |_| {
+            notifier.stopping();
+            Ok::<(), ()>(())
         })
     };
+    let deadline = {
+        use tokio_timer::Delay;
+
+        let logger = logger.clone();
+        let deadline_at = deadline_at.clone();
+        shutdown
+            .then(move |_| {
+                let at = Instant::now() + shutdown_timeout;
+                if let Ok(mut deadline_at) = deadline_at.lock() {
+                    *deadline_at = Some(at);
+                }
+                Delay::new(at)
+            })
+            .then(move |_| {
+                warn!(logger, "shutdown deadline expired, aborting pending requests");
+                Ok(())
+            })
+    };
+
+    let server = match acceptor {
+        Some(acceptor) => {
+            let incoming = tls_incoming(incoming, acceptor.clone(), logger.clone());
+            runtime.spawn(reload_on_sighup(acceptor, logger.clone()));
+            Box::new(serve(incoming, context, stop_accepting)) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
+        None => Box::new(serve(incoming, context, stop_accepting)),
+    };
+
+    if let Some(interval) = notifier.watchdog_interval() {
+        use tokio_timer::Interval;
+
+        let notifier = notifier.clone();
+        let logger = logger.clone();
+        runtime.spawn(
+            Interval::new_interval(interval)
+                .for_each(move |_| {
+                    notifier.watchdog();
+                    Ok(())
+                })
+                .map_err(move |e| {
+                    log_error(&logger, &Error::with_chain(e, "Error pinging systemd watchdog"));
+                }),
+        );
+    }
+
+    notifier.ready();
+    let _ = runtime.block_on(server.select(deadline).then(|_| Ok::<(), ()>(())));
+    let _ = runtime.shutdown_now().wait();
+    info!(logger, "server stopped");
+
+    let deadline = deadline_at.lock().ok().and_then(|deadline_at| *deadline_at);
+    Ok(deadline.unwrap_or_else(|| Instant::now() + shutdown_timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, DnsServiceConfig, DomainConfig, HetznerDnsConfig, IpResolvMethod,
+                 UserConfig};
+    use password::Password;
+    use slog;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+
+    struct BlockingService(Mutex<mpsc::Receiver<()>>);
+
+    impl DnsService for BlockingService {
+        fn update(&self, _: &IpAddr) -> Result<Update> {
+            self.0.lock().unwrap().recv().chain_err(|| "Channel closed")?;
+            Ok(Update::Published)
+        }
+    }
+
+    fn domain(name: &str, allowed_clients: &[&str]) -> DomainConfig {
+        DomainConfig {
+            name: name.to_owned(),
+            hetzner_user: None,
+            template: None,
+            allowed_clients: allowed_clients.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn user(name: &str, password: &str, hostnames: &[&str]) -> UserConfig {
+        UserConfig {
+            name: name.to_owned(),
+            password: Password::Plain(password.to_owned()),
+            hostnames: hostnames.iter().map(|h| h.to_string()).collect(),
+            allowed_networks: Vec::new(),
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            domains: vec![domain("example.com", &[])],
+            server_addr: "127.0.0.1:0".to_owned(),
+            tls: None,
+            users: vec![user("user", "pass", &["example.com"])],
+            ip_resolv: IpResolvMethod::DynDns2,
+            dns_service: DnsServiceConfig::HetznerDns(HetznerDnsConfig {
+                api_token: "token".to_owned(),
+                api_url: "http://127.0.0.1:0".to_owned(),
+                zone: None,
+                ttl: None,
+            }),
+            state_file: None,
+            worker_threads: 2,
+            min_update_interval: 0,
+            auth_max_failures: 3,
+            auth_failure_window: 600,
+            shutdown_timeout: 1,
+            ip_peer_fallback: false,
+            trusted_proxies: Vec::new(),
+            denied_networks: Vec::new(),
+        }
+    }
+
+    fn context<S>(services: Vec<(&str, S)>, config: Config) -> Arc<Context<S>> {
+        let lockout = Lockout::new(config.auth_max_failures, config.auth_failure_window);
+
+        Arc::new(Context {
+            logger: slog::Logger::root(slog::Discard, o!()),
+            config: Arc::new(config),
+            state: Arc::new(StateStore::load(None::<PathBuf>).unwrap()),
+            services: services
+                .into_iter()
+                .map(|(domain, service)| (domain.to_owned(), service))
+                .collect(),
+            pool: CpuPool::new(2),
+            errors: Arc::new(LastErrors::default()),
+            metrics: Arc::new(Metrics::default()),
+            lockout,
+            notifier: Arc::new(Notifier::default()),
+        })
+    }
+
+    struct NoopService;
+
+    impl DnsService for NoopService {
+        fn update(&self, _: &IpAddr) -> Result<Update> {
+            Ok(Update::Published)
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        "192.0.2.99:1234".parse().unwrap()
+    }
+
+    fn request(query: &str, authorization: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder();
+        builder.uri(format!("/nic/update?{}", query));
+        builder.header(::hyper::header::USER_AGENT, "test/1.0");
+        if let Some(authorization) = authorization {
+            let token = format!("Basic {}", ::base64::encode(authorization));
+            builder.header(::hyper::header::AUTHORIZATION, token.as_str());
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn body(response: ResponseFuture) -> String {
+        let body = response.wait().unwrap().into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn pending_update_does_not_block_requests() {
+        let (tx, rx) = mpsc::channel();
+        let context = context(vec![("example.com", BlockingService(Mutex::new(rx)))], config());
+
+        let pending = handle_request(
+            request("hostname=example.com&myip=192.0.2.1", Some("user:pass")),
+            peer(),
+            &context,
+        );
+
+        let unauthorized = handle_request(request("hostname=example.com", None), peer(), &context);
+        assert_eq!(body(unauthorized), "badauth");
+
+        tx.send(()).unwrap();
+        assert_eq!(body(pending), "good 192.0.2.1");
+
+        let repeated = handle_request(
+            request("hostname=example.com&myip=192.0.2.1", Some("user:pass")),
+            peer(),
+            &context,
+        );
+        assert_eq!(body(repeated), "nochg 192.0.2.1");
+    }
 
-    let server = Server::bind(&addr).serve(new_service);
+    fn update(context: &Arc<Context<NoopService>>, query: &str) -> String {
+        body(handle_request(request(query, Some("user:pass")), peer(), context))
+    }
+
+    #[test]
+    fn return_codes() {
+        let context = context(vec![("example.com", NoopService)], config());
+
+        assert_eq!(update(&context, "myip=192.0.2.1"), "notfqdn");
+        assert_eq!(update(&context, "hostname=example&myip=192.0.2.1"), "notfqdn");
+        assert_eq!(update(&context, "hostname=example.org&myip=192.0.2.1"), "nohost");
+        assert_eq!(update(&context, "hostname=example.com&myip=foo"), "badrequest");
+        assert_eq!(update(&context, "hostname=Example.com.&myip=2001:db8::1"), "good 2001:db8::1");
+
+        let mut anonymous = request("hostname=example.com&myip=192.0.2.1", Some("user:pass"));
+        anonymous.headers_mut().remove(::hyper::header::USER_AGENT);
+        assert_eq!(body(handle_request(anonymous, peer(), &context)), "badagent");
+
+        let wrong_password = request("hostname=example.com&myip=192.0.2.1", Some("user:wrong"));
+        assert_eq!(body(handle_request(wrong_password, peer(), &context)), "badauth");
+    }
+
+    struct QueuingService;
+
+    impl DnsService for QueuingService {
+        fn update(&self, _: &IpAddr) -> Result<Update> {
+            Ok(Update::Queued)
+        }
+    }
+
+    #[test]
+    fn queued_updates_are_not_recorded() {
+        let context = context(vec![("example.com", QueuingService)], config());
+        let queued = || {
+            body(handle_request(
+                request("hostname=example.com&myip=192.0.2.1", Some("user:pass")),
+                peer(),
+                &context,
+            ))
+        };
+
+        assert_eq!(queued(), "good 192.0.2.1");
+        assert_eq!(context.state.get("example.com").unwrap().ipv4, None);
+        // not answered with nochg, the address hasn't been published yet
+        assert_eq!(queued(), "good 192.0.2.1");
+    }
+
+    #[test]
+    fn frequent_updates_are_abuse() {
+        let mut config = config();
+        config.min_update_interval = 3600;
+        let context = context(vec![("example.com", NoopService)], config);
+
+        assert_eq!(update(&context, "hostname=example.com&myip=192.0.2.1"), "good 192.0.2.1");
+        assert_eq!(update(&context, "hostname=example.com&myip=192.0.2.1"), "nochg 192.0.2.1");
+        assert_eq!(update(&context, "hostname=example.com&myip=192.0.2.2"), "abuse");
+    }
+
+    #[test]
+    fn dual_stack_updates_are_no_abuse() {
+        let mut config = config();
+        config.min_update_interval = 3600;
+        let context = context(vec![("example.com", NoopService)], config);
+
+        assert_eq!(update(&context, "hostname=example.com&myip=192.0.2.1"), "good 192.0.2.1");
+        assert_eq!(update(&context, "hostname=example.com&myip=2001:db8::1"), "good 2001:db8::1");
+        assert_eq!(update(&context, "hostname=example.com&myip=2001:db8::2"), "abuse");
+        assert_eq!(update(&context, "hostname=example.com&myip=192.0.2.2"), "abuse");
+    }
+
+    #[test]
+    fn one_line_per_hostname() {
+        let context = context(vec![("example.com", NoopService)], config());
+
+        assert_eq!(
+            update(&context, "hostname=example.org,example.com,foo,example.com&myip=192.0.2.1"),
+            "nohost\ngood 192.0.2.1\nnotfqdn\nnochg 192.0.2.1"
+        );
+
+        let hostnames = vec!["example.com"; MAX_HOSTNAMES + 1].join(",");
+        assert_eq!(update(&context, &format!("hostname={}&myip=192.0.2.1", hostnames)), "numhost");
+    }
+
+    #[test]
+    fn hostnames_are_routed_to_their_domain() {
+        let mut config = config();
+        config.domains.push(domain("example.net", &["user"]));
+        config.domains.push(domain("example.org", &["other"]));
+        config.users[0].hostnames = vec![
+            "example.com".to_owned(),
+            "example.net".to_owned(),
+            "example.org".to_owned(),
+        ];
+        let context = context(
+            vec![
+                ("example.com", NoopService),
+                ("example.net", NoopService),
+                ("example.org", NoopService),
+            ],
+            config,
+        );
+
+        assert_eq!(
+            update(&context, "hostname=example.net,example.org,example.com&myip=192.0.2.1"),
+            "good 192.0.2.1\nnohost\ngood 192.0.2.1"
+        );
+        assert_eq!(update(&context, "hostname=example.net&myip=192.0.2.1"), "nochg 192.0.2.1");
+    }
+
+    #[test]
+    fn users_only_update_their_hostnames() {
+        let mut config = config();
+        config.domains.push(domain("example.net", &[]));
+        config.users.push(user("router", "secret", &["example.net"]));
+        let context = context(
+            vec![("example.com", NoopService), ("example.net", NoopService)],
+            config,
+        );
+
+        let router = |query: &str| {
+            body(handle_request(request(query, Some("router:secret")), peer(), &context))
+        };
+
+        assert_eq!(
+            router("hostname=example.com,example.net&myip=192.0.2.1"),
+            "nohost\ngood 192.0.2.1"
+        );
+        assert_eq!(update(&context, "hostname=example.net&myip=192.0.2.2"), "nohost");
+        assert_eq!(router("hostname=example.net&myip=192.0.2.2"), "good 192.0.2.2");
+        let wrong_password = request("hostname=example.net", Some("router:pass"));
+        assert_eq!(body(handle_request(wrong_password, peer(), &context)), "badauth");
+    }
+
+    #[test]
+    fn peer_address_fallback() {
+        let without_fallback = context(vec![("example.com", NoopService)], config());
+        assert_eq!(update(&without_fallback, "hostname=example.com"), "badrequest");
+
+        let mut config = config();
+        config.ip_peer_fallback = true;
+        let context = context(vec![("example.com", NoopService)], config);
+        assert_eq!(update(&context, "hostname=example.com"), "good 192.0.2.99");
+        assert_eq!(update(&context, "hostname=example.com&myip=2001:db8::1"), "good 2001:db8::1");
+
+    }
+
+    #[test]
+    fn forwarded_addresses_need_trusted_peer() {
+        let mut config = config();
+        config.ip_resolv = IpResolvMethod::Header("X-Forwarded-For".to_owned());
+        config.trusted_proxies = vec!["192.0.2.0/24".parse().unwrap()];
+        let context = context(vec![("example.com", NoopService)], config);
+
+        let forwarded = |peer: &str, value: &'static str| {
+            let mut request = request("", Some("user:pass"));
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", value.parse().unwrap());
+            body(handle_request(request, peer.parse().unwrap(), &context))
+        };
+
+        assert_eq!(forwarded("198.51.100.1:1234", "203.0.113.1"), "badrequest");
+        assert_eq!(
+            forwarded("192.0.2.10:1234", "203.0.113.1, 198.51.100.1, 192.0.2.11"),
+            "good 198.51.100.1"
+        );
+        assert_eq!(forwarded("[::ffff:192.0.2.10]:1234", "203.0.113.1"), "good 203.0.113.1");
+    }
+
+    #[test]
+    fn address_policy() {
+        let mut config = config();
+        config.denied_networks = ::policy::bogons();
+        config.users[0].allowed_networks = vec!["93.184.216.0/24".parse().unwrap()];
+        let context = context(vec![("example.com", NoopService)], config);
+
+        assert_eq!(update(&context, "hostname=example.com&myip=192.168.0.1"), "badaddr 192.168.0.1");
+        assert_eq!(update(&context, "hostname=example.com&myip=8.8.8.8"), "badaddr 8.8.8.8");
+        assert_eq!(update(&context, "hostname=example.com&myip=93.184.216.34"), "good 93.184.216.34");
+    }
 
-    ::hyper::rt::run(server.map_err(|e| {
-        eprintln!("server error: {}", e);
-    }));
+    struct FailingService;
 
-    Ok(())
+    impl DnsService for FailingService {
+        fn update(&self, _: &IpAddr) -> Result<Update> {
+            bail!("Connection refused")
+        }
+
+        fn check(&self) -> Vec<(&'static str, Result<()>)> {
+            vec![("smtp", Err("Connection refused".into()))]
+        }
+    }
+
+    fn get<S>(context: &Arc<Context<S>>, path: &str, authorization: Option<&str>) -> Response<Body>
+    where
+        S: DnsService + Send + Sync + 'static,
+    {
+        let mut builder = Request::builder();
+        builder.uri(path);
+        builder.header(::hyper::header::USER_AGENT, "test/1.0");
+        if let Some(authorization) = authorization {
+            let token = format!("Basic {}", ::base64::encode(authorization));
+            builder.header(::hyper::header::AUTHORIZATION, token.as_str());
+        }
+        route(builder.body(()).unwrap(), peer(), context).wait().unwrap()
+    }
+
+    #[test]
+    fn health_and_status() {
+        let mut config = config();
+        config.domains.push(domain("example.org", &[]));
+        config.users[0].hostnames.push("example.org".to_owned());
+        config.users.push(user("other", "secret", &["example.org"]));
+        let context = context(
+            vec![("example.com", FailingService), ("example.org", FailingService)],
+            config,
+        );
+
+        let health = get(&context, "/healthz", None);
+        assert_eq!(health.status(), StatusCode::OK);
+
+        let anonymous = get(&context, "/status", None);
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+
+        let request = get(&context, "/nic/update?hostname=example.org&myip=93.184.216.34", Some("other:secret"));
+        let _ = request.into_body().concat2().wait().unwrap();
+
+        let status = get(&context, "/status", Some("other:secret"));
+        assert_eq!(status.status(), StatusCode::OK);
+        let status = status.into_body().concat2().wait().unwrap();
+        let status: ::serde_json::Value = ::serde_json::from_slice(&status).unwrap();
+
+        let domains = status.as_object().unwrap();
+        assert_eq!(domains.keys().collect::<Vec<_>>(), vec!["example.org"]);
+        assert!(status["example.org"]["ipv4"].is_null());
+        assert_eq!(status["example.org"]["last_error"]["message"], "Connection refused");
+        assert_eq!(status["example.org"]["checks"]["smtp"]["ok"], false);
+    }
+
+    #[test]
+    fn metrics() {
+        let context = context(vec![("example.com", NoopService)], config());
+
+        update(&context, "hostname=example.com&myip=93.184.216.34");
+        update(&context, "hostname=example.com&myip=93.184.216.34");
+        get(&context, "/status", Some("user:wrong"));
+
+        let metrics = get(&context, "/metrics", None);
+        assert_eq!(metrics.status(), StatusCode::OK);
+        let metrics = metrics.into_body().concat2().wait().unwrap();
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+
+        for expected in &[
+            "dyndns_requests_total{code=\"good\"} 1",
+            "dyndns_requests_total{code=\"nochg\"} 1",
+            "dyndns_auth_failures_total 1",
+            "dyndns_updates_total{domain=\"example.com\",code=\"good\"} 1",
+            "dyndns_updates_total{domain=\"example.com\",code=\"nochg\"} 1",
+        ] {
+            assert!(metrics.lines().any(|line| line == *expected), "{} missing in\n{}", expected, metrics);
+        }
+    }
+
+    #[test]
+    fn unknown_users_cost_the_slowest_hash() {
+        let bcrypt = ::bcrypt::hash("secret", 6).unwrap();
+        let argon2 = ::argon2::hash_encoded(b"secret", b"somesalt", &Default::default()).unwrap();
+
+        // the legacy plaintext user comes first
+        let mut config = config();
+        for (name, hash) in vec![("router", &bcrypt), ("nas", &argon2)] {
+            let mut user = user(name, "", &["example.com"]);
+            user.password = Password::from_hash(hash).unwrap();
+            config.users.push(user);
+        }
+        match slowest_password(&config) {
+            Some(&Password::Argon2(_)) => {}
+            password => panic!("unexpected {:?}", password),
+        }
+
+        config.users[1].password = Password::from_hash(&::bcrypt::hash("secret", 10).unwrap()).unwrap();
+        match slowest_password(&config) {
+            Some(&Password::Bcrypt(_)) => {}
+            password => panic!("unexpected {:?}", password),
+        }
+
+        let context = context(vec![("example.com", NoopService)], config);
+        let unknown = request("hostname=example.com&myip=93.184.216.34", Some("nobody:secret"));
+        assert_eq!(body(handle_request(unknown, peer(), &context)), "badauth");
+    }
+
+    #[test]
+    fn failed_logins_lock_out_client() {
+        let context = context(vec![("example.com", NoopService)], config());
+        let login = |authorization, peer: &str| {
+            let request = request("hostname=example.com&myip=93.184.216.34", authorization);
+            body(handle_request(request, peer.parse().unwrap(), &context))
+        };
+
+        assert_eq!(login(None, "192.0.2.99:1234"), "badauth");
+        assert_eq!(login(None, "192.0.2.99:1234"), "badauth");
+        assert_eq!(login(None, "192.0.2.99:1234"), "badauth");
+        assert_eq!(login(Some("user:wrong"), "192.0.2.99:1234"), "badauth");
+        assert_eq!(login(Some("user:pass"), "192.0.2.99:1234"), "good 93.184.216.34");
+
+        for _ in 0..3 {
+            assert_eq!(login(Some("user:wrong"), "192.0.2.99:1234"), "badauth");
+        }
+        assert_eq!(login(Some("user:pass"), "192.0.2.99:1234"), "abuse");
+        assert_eq!(get(&context, "/status", Some("user:pass")).status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(login(Some("user:pass"), "198.51.100.2:1234"), "nochg 93.184.216.34");
+    }
+
+    #[test]
+    fn serve_stops_on_shutdown() {
+        use futures::sync::oneshot;
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let context = context(vec![("example.com", NoopService)], config());
+        let incoming = accept(listener, context.logger.clone()).unwrap();
+
+        let (stop, shutdown) = oneshot::channel();
+        let (notify_stopped, stopped) = mpsc::channel();
+        let mut runtime = ::tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(serve(incoming, context, shutdown).then(move |_| {
+            notify_stopped.send(()).unwrap();
+            Ok(())
+        }));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /healthz HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+
+        stop.send(()).unwrap();
+        stopped.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
 }
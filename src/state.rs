@@ -0,0 +1,231 @@
+use errors::*;
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Last successfully published addresses of a domain.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DomainState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+    /// Time of the last update of any family
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv4_updated: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_updated: Option<i64>,
+}
+
+impl DomainState {
+    /// Returns the published address of the same family as `addr`.
+    pub fn address(&self, addr: &IpAddr) -> Option<IpAddr> {
+        match *addr {
+            IpAddr::V4(_) => self.ipv4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.ipv6.map(IpAddr::V6),
+        }
+    }
+
+    /// Returns the time of the last update of the same family as `addr`. State files
+    /// written before the families were tracked separately only know `updated`.
+    pub fn updated_at(&self, addr: &IpAddr) -> Option<i64> {
+        let updated = match *addr {
+            IpAddr::V4(_) => self.ipv4_updated,
+            IpAddr::V6(_) => self.ipv6_updated,
+        };
+
+        if self.ipv4_updated.is_none() && self.ipv6_updated.is_none() {
+            self.updated
+        } else {
+            updated
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StateFile {
+    domains: BTreeMap<String, DomainState>,
+}
+
+pub struct StateStore {
+    path: Option<PathBuf>,
+    state: Mutex<StateFile>,
+}
+
+impl StateStore {
+    /// Loads the state from `path`. Without a path the state is only kept in memory.
+    pub fn load<P: AsRef<Path>>(path: Option<P>) -> Result<Self> {
+        use std::fs::File;
+        use std::io::{ErrorKind, Read};
+        use toml::de::from_str;
+
+        let path = path.map(|p| p.as_ref().to_path_buf());
+
+        let state = match path {
+            Some(ref path) => match File::open(path) {
+                Ok(mut file) => {
+                    let mut buffer = String::new();
+                    file.read_to_string(&mut buffer)
+                        .chain_err(|| "Error reading state file")?;
+                    from_str(&buffer).chain_err(|| "Error parsing state file")?
+                }
+                Err(ref e) if e.kind() == ErrorKind::NotFound => StateFile::default(),
+                Err(e) => return Err(e).chain_err(|| "Error opening state file"),
+            },
+            None => StateFile::default(),
+        };
+
+        Ok(StateStore {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn get(&self, domain: &str) -> Result<DomainState> {
+        let state = self.state.lock().map_err(|_| "State lock poisoned")?;
+        Ok(state.domains.get(domain).cloned().unwrap_or_default())
+    }
+
+    /// Records `addr` as published for `domain` and persists the state.
+    pub fn record(&self, domain: &str, addr: &IpAddr) -> Result<()> {
+        use chrono::Utc;
+
+        let mut state = self.state.lock().map_err(|_| "State lock poisoned")?;
+        {
+            let now = Utc::now().timestamp();
+            let entry = state.domains.entry(domain.to_owned()).or_insert_with(Default::default);
+            match *addr {
+                IpAddr::V4(addr) => {
+                    entry.ipv4 = Some(addr);
+                    entry.ipv4_updated = Some(now);
+                }
+                IpAddr::V6(addr) => {
+                    entry.ipv6 = Some(addr);
+                    entry.ipv6_updated = Some(now);
+                }
+            }
+            entry.updated = Some(now);
+        }
+
+        match self.path {
+            Some(ref path) => persist(path, &state),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the state again, e.g. when a previous write failed.
+    pub fn flush(&self) -> Result<()> {
+        let state = self.state.lock().map_err(|_| "State lock poisoned")?;
+
+        match self.path {
+            Some(ref path) => persist(path, &state),
+            None => Ok(()),
+        }
+    }
+}
+
+fn persist(path: &Path, state: &StateFile) -> Result<()> {
+    use toml::ser::to_string;
+
+    let content = to_string(state).chain_err(|| "Error serializing state")?;
+    write_atomically(path, &content)
+}
+
+/// Writes to a temporary file first, so a crash never leaves a truncated file behind.
+pub fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    {
+        let mut file = File::create(&tmp_path).chain_err(|| "Error creating file")?;
+        file.write_all(content.as_bytes())
+            .chain_err(|| "Error writing file")?;
+        file.sync_all().chain_err(|| "Error syncing file")?;
+    }
+
+    fs::rename(&tmp_path, path).chain_err(|| "Error replacing file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn is_current(store: &StateStore, domain: &str, addr: &IpAddr) -> bool {
+        store.get(domain).unwrap().address(addr) == Some(*addr)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dyndns-daemon-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn missing_file_is_empty_state() {
+        let path = temp_path("missing");
+        let store = StateStore::load(Some(&path)).unwrap();
+
+        assert_eq!(store.get("example.com").unwrap(), DomainState::default());
+    }
+
+    #[test]
+    fn update_times_per_family() {
+        let store = StateStore::load(None::<PathBuf>).unwrap();
+        let v4 = "192.0.2.1".parse().unwrap();
+        let v6 = "2001:db8::1".parse().unwrap();
+
+        store.record("example.com", &v4).unwrap();
+        let state = store.get("example.com").unwrap();
+        assert!(state.updated_at(&v4).is_some());
+        assert_eq!(state.updated_at(&v6), None);
+
+        let legacy = DomainState {
+            updated: Some(1000),
+            ..DomainState::default()
+        };
+        assert_eq!(legacy.updated_at(&v4), Some(1000));
+        assert_eq!(legacy.updated_at(&v6), Some(1000));
+    }
+
+    #[test]
+    fn recorded_addresses_are_current() {
+        let store = StateStore::load(None::<PathBuf>).unwrap();
+        let v4 = "192.0.2.1".parse().unwrap();
+        let v6 = "2001:db8::1".parse().unwrap();
+
+        assert!(!is_current(&store, "example.com", &v4));
+
+        store.record("example.com", &v4).unwrap();
+        store.record("example.com", &v6).unwrap();
+
+        assert!(is_current(&store, "example.com", &v4));
+        assert!(is_current(&store, "example.com", &v6));
+        assert!(!is_current(&store, "example.org", &v4));
+        assert!(!is_current(&store, "example.com", &"192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn state_survives_reload() {
+        let path = temp_path("reload");
+        let addr = "192.0.2.1".parse().unwrap();
+
+        StateStore::load(Some(&path))
+            .unwrap()
+            .record("example.com", &addr)
+            .unwrap();
+
+        let store = StateStore::load(Some(&path)).unwrap();
+        assert!(is_current(&store, "example.com", &addr));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use chrono::{TimeZone, Utc};
+use errors::*;
+use state::DomainState;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+/// Entry of a domain in the `/status` response.
+#[derive(Serialize, Debug)]
+pub struct DomainStatus {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    pub last_update: Option<String>,
+    pub last_error: Option<ErrorStatus>,
+    pub checks: BTreeMap<&'static str, CheckStatus>,
+}
+
+impl DomainStatus {
+    pub fn new(
+        published: &DomainState,
+        last_error: Option<ErrorStatus>,
+        checks: Vec<(&'static str, Result<()>)>,
+    ) -> Self {
+        DomainStatus {
+            ipv4: published.ipv4,
+            ipv6: published.ipv6,
+            last_update: published.updated.map(format_time),
+            last_error,
+            checks: checks
+                .into_iter()
+                .map(|(name, result)| (name, CheckStatus::from(result)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorStatus {
+    pub time: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CheckStatus {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<()>> for CheckStatus {
+    fn from(result: Result<()>) -> Self {
+        CheckStatus {
+            ok: result.is_ok(),
+            error: result.err().map(|e| message(&e)),
+        }
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    Utc.timestamp(timestamp, 0).to_rfc3339()
+}
+
+/// Joins the chain of causes into one line.
+fn message(e: &Error) -> String {
+    e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")
+}
+
+/// Remembers the last failed update of each domain.
+#[derive(Default)]
+pub struct LastErrors {
+    errors: Mutex<BTreeMap<String, ErrorStatus>>,
+}
+
+impl LastErrors {
+    pub fn record(&self, domain: &str, e: &Error) {
+        let error = ErrorStatus {
+            time: format_time(Utc::now().timestamp()),
+            message: message(e),
+        };
+
+        if let Ok(mut errors) = self.errors.lock() {
+            errors.insert(domain.to_owned(), error);
+        }
+    }
+
+    pub fn get(&self, domain: &str) -> Option<ErrorStatus> {
+        self.errors
+            .lock()
+            .ok()
+            .and_then(|errors| errors.get(domain).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_include_causes() {
+        let errors = LastErrors::default();
+        let e: Error = Error::from("Connection refused").chain_err(|| "Error sending mail");
+
+        errors.record("example.com", &e);
+
+        assert_eq!(
+            errors.get("example.com").unwrap().message,
+            "Error sending mail: Connection refused"
+        );
+        assert!(errors.get("example.org").is_none());
+    }
+
+    #[test]
+    fn checks() {
+        assert_eq!(
+            CheckStatus::from(Err("gpg not found".into())),
+            CheckStatus {
+                ok: false,
+                error: Some("gpg not found".to_owned()),
+            }
+        );
+        assert_eq!(format_time(0), "1970-01-01T00:00:00+00:00");
+    }
+}
@@ -0,0 +1,259 @@
+use errors::*;
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3).
+const LISTEN_FDS_START: i32 = 3;
+
+/// Returns the listening socket passed by systemd socket activation, if any.
+pub fn listen_fds() -> Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    let count = listen_fds_count(
+        env::var("LISTEN_PID").ok().as_ref().map(String::as_str),
+        env::var("LISTEN_FDS").ok().as_ref().map(String::as_str),
+        ::std::process::id(),
+    )?;
+
+    // the variables are meant for this process only, not for children like gpg
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    match count {
+        0 => Ok(None),
+        1 => {
+            check_listening(LISTEN_FDS_START)?;
+            let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+            // like sd_listen_fds, so children like gpg don't inherit the socket
+            set_cloexec(LISTEN_FDS_START).chain_err(|| "Error configuring socket passed by systemd")?;
+            listener
+                .local_addr()
+                .chain_err(|| "Socket passed by systemd is no TCP socket")?;
+            Ok(Some(listener))
+        }
+        n => bail!("Expected one socket from systemd, got {}", n),
+    }
+}
+
+/// Checks that `fd` is a listening stream socket, e.g. not a UDP socket from `ListenDatagram`.
+fn check_listening(fd: i32) -> Result<()> {
+    use libc::{SOCK_STREAM, SO_ACCEPTCONN, SO_TYPE};
+
+    let socket_type = socket_option(fd, SO_TYPE).chain_err(|| "File passed by systemd is no socket")?;
+    if socket_type != SOCK_STREAM {
+        bail!("Socket passed by systemd is no stream socket");
+    }
+    if socket_option(fd, SO_ACCEPTCONN).chain_err(|| "Error checking socket passed by systemd")? == 0 {
+        bail!("Socket passed by systemd isn't listening");
+    }
+    Ok(())
+}
+
+fn socket_option(fd: i32, option: i32) -> io::Result<i32> {
+    use libc::{getsockopt, socklen_t, SOL_SOCKET};
+    use std::mem::size_of;
+
+    let mut value: i32 = 0;
+    let mut len = size_of::<i32>() as socklen_t;
+    let result = unsafe { getsockopt(fd, SOL_SOCKET, option, &mut value as *mut i32 as *mut _, &mut len) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn set_cloexec(fd: i32) -> io::Result<()> {
+    use libc::{fcntl, FD_CLOEXEC, F_GETFD, F_SETFD};
+
+    let flags = unsafe { fcntl(fd, F_GETFD) };
+    if flags < 0 || unsafe { fcntl(fd, F_SETFD, flags | FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Number of sockets passed to the process `pid`.
+fn listen_fds_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Result<usize> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0),
+    };
+
+    let listen_pid: u32 = listen_pid.parse().chain_err(|| "Invalid LISTEN_PID")?;
+    if listen_pid != pid {
+        return Ok(0);
+    }
+
+    listen_fds.parse().chain_err(|| "Invalid LISTEN_FDS")
+}
+
+/// Sends state changes to the service manager, see sd_notify(3).
+/// Without `NOTIFY_SOCKET` nothing is sent.
+#[derive(Default)]
+pub struct Notifier {
+    socket: Option<UnixDatagram>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Result<Self> {
+        let notifier = match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => Notifier::connect(&path.to_string_lossy())?,
+            None => Notifier::default(),
+        };
+        env::remove_var("NOTIFY_SOCKET");
+        Ok(notifier)
+    }
+
+    fn connect(path: &str) -> Result<Self> {
+        let socket = UnixDatagram::unbound().chain_err(|| "Error creating notify socket")?;
+
+        // the abstract namespace, as used in containers
+        if path.starts_with('@') {
+            connect_abstract(&socket, &path[1..])
+        } else {
+            socket.connect(path)
+        }.chain_err(|| format!("Error connecting to NOTIFY_SOCKET {}", path))?;
+
+        Ok(Notifier {
+            socket: Some(socket),
+        })
+    }
+
+    /// Sends `state`, e.g. `READY=1`. Errors are ignored, the service works without manager.
+    pub fn notify(&self, state: &str) {
+        if let Some(ref socket) = self.socket {
+            let _ = socket.send(state.as_bytes());
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    pub fn status(&self, status: &str) {
+        // a newline would start a new variable
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")));
+    }
+
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    /// Interval in which the watchdog has to be pinged, half of `WATCHDOG_USEC`.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.socket.as_ref()?;
+
+        let pid = env::var("WATCHDOG_PID").ok();
+        if pid.map_or(false, |pid| pid != ::std::process::id().to_string()) {
+            return None;
+        }
+
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        Some(Duration::from_micros(usec / 2))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(socket: &UnixDatagram, name: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    socket.connect_addr(&SocketAddr::from_abstract_name(name.as_bytes())?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_: &UnixDatagram, _: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Abstract sockets are only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_count() {
+        assert_eq!(listen_fds_count(None, None, 42).unwrap(), 0);
+        assert_eq!(listen_fds_count(Some("42"), Some("1"), 42).unwrap(), 1);
+        assert_eq!(listen_fds_count(Some("43"), Some("1"), 42).unwrap(), 0);
+        assert!(listen_fds_count(Some("42"), Some("one"), 42).is_err());
+    }
+
+    #[test]
+    fn notifications() {
+        let path = env::temp_dir().join(format!("dyndns-daemon-notify-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::connect(&path.to_string_lossy()).unwrap();
+        notifier.ready();
+        notifier.status("example.com: good 93.184.216.34\nWATCHDOG=1");
+
+        let mut buffer = [0; 128];
+        let n = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"READY=1");
+        let n = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], &b"STATUS=example.com: good 93.184.216.34 WATCHDOG=1"[..]);
+
+        ::std::fs::remove_file(&path).unwrap();
+        Notifier::default().ready();
+
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn abstract_notify_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let name = format!("dyndns-daemon-notify-{}", ::std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let manager = UnixDatagram::bind_addr(&addr).unwrap();
+
+        Notifier::connect(&format!("@{}", name)).unwrap().ready();
+
+        let mut buffer = [0; 16];
+        let n = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"READY=1");
+    }
+
+    #[test]
+    fn only_listening_stream_sockets() {
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(check_listening(listener.as_raw_fd()).is_ok());
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(check_listening(udp.as_raw_fd()).is_err());
+
+        let connected = ::std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(check_listening(connected.as_raw_fd()).is_err());
+
+        let file = ::std::fs::File::open("Cargo.toml").unwrap();
+        assert!(check_listening(file.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn close_on_exec() {
+        use libc::{close, dup, fcntl, FD_CLOEXEC, F_GETFD};
+        use std::os::unix::io::AsRawFd;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // unlike the sockets of std, a duplicate lacks the flag, as systemd's do
+        let fd = unsafe { dup(listener.as_raw_fd()) };
+        assert_eq!(unsafe { fcntl(fd, F_GETFD) } & FD_CLOEXEC, 0);
+
+        set_cloexec(fd).unwrap();
+        assert_eq!(unsafe { fcntl(fd, F_GETFD) } & FD_CLOEXEC, FD_CLOEXEC);
+        unsafe { close(fd) };
+    }
+}
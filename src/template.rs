@@ -8,17 +8,53 @@ use chrono::*;
 enum TemplateSegment {
     Static(String),
     Ip,
+    Ip6,
     Serial,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Addresses {
+    pub v4: Option<net::Ipv4Addr>,
+    pub v6: Option<net::Ipv6Addr>,
+}
+
+impl Addresses {
+    pub fn set(&mut self, addr: &net::IpAddr) {
+        match *addr {
+            net::IpAddr::V4(addr) => self.v4 = Some(addr),
+            net::IpAddr::V6(addr) => self.v6 = Some(addr),
+        }
+    }
+
+    /// The known addresses, IPv4 first.
+    pub fn to_vec(&self) -> Vec<net::IpAddr> {
+        self.v4
+            .map(net::IpAddr::V4)
+            .into_iter()
+            .chain(self.v6.map(net::IpAddr::V6))
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Template {
     segments: Vec<TemplateSegment>,
 }
 
 impl Template {
-    pub fn render(&self, ip: &net::Ipv4Addr, now: DateTime<Utc>) -> Result<String> {
+    /// Whether the template has a placeholder for the family of `addr`.
+    pub fn includes(&self, addr: &net::IpAddr) -> bool {
+        self.segments.iter().any(|segment| match (segment, addr) {
+            (&TemplateSegment::Ip, &net::IpAddr::V4(_)) | (&TemplateSegment::Ip6, &net::IpAddr::V6(_)) => true,
+            _ => false,
+        })
+    }
+
+    /// Renders the template, leaving out the lines with a placeholder of a family
+    /// without address, e.g. the AAAA record before the first IPv6 update.
+    pub fn render(&self, addrs: &Addresses, now: DateTime<Utc>) -> Result<String> {
         const MAX_IP_SIZE: usize = 15;
+        const MAX_IP6_SIZE: usize = 39;
         const MAX_SERIAL_SIZE: usize = 19;
 
         let mut size = 0;
@@ -26,27 +62,51 @@ impl Template {
         for segment in &self.segments {
             match *segment {
                 TemplateSegment::Ip => size += MAX_IP_SIZE,
+                TemplateSegment::Ip6 => size += MAX_IP6_SIZE,
                 TemplateSegment::Serial => size += MAX_SERIAL_SIZE,
                 TemplateSegment::Static(ref s) => size += s.len(),
             }
         }
 
         let mut buffer = String::with_capacity(size);
+        let mut line = String::new();
+        let mut complete = true;
 
         for segment in &self.segments {
             use std::fmt::Write;
 
             match *segment {
-                TemplateSegment::Ip => {
-                    write!(buffer, "{}", ip).chain_err(|| "Error formatting ip address")?
-                }
+                TemplateSegment::Ip => match addrs.v4 {
+                    Some(ip) => write!(line, "{}", ip).chain_err(|| "Error formatting ip address")?,
+                    None => complete = false,
+                },
+                TemplateSegment::Ip6 => match addrs.v6 {
+                    Some(ip) => write!(line, "{}", ip).chain_err(|| "Error formatting ipv6 address")?,
+                    None => complete = false,
+                },
                 TemplateSegment::Serial => {
-                    write!(buffer, "{}", now.timestamp()).chain_err(|| "Error formatting serial")?
+                    write!(line, "{}", now.timestamp()).chain_err(|| "Error formatting serial")?
+                }
+                TemplateSegment::Static(ref s) => {
+                    let mut rest = &s[..];
+                    while let Some(end) = rest.find('\n') {
+                        line += &rest[..=end];
+                        if complete {
+                            buffer += &line;
+                        }
+                        line.clear();
+                        complete = true;
+                        rest = &rest[end + 1..];
+                    }
+                    line += rest;
                 }
-                TemplateSegment::Static(ref s) => buffer += s,
             };
         }
 
+        if complete {
+            buffer += &line;
+        }
+
         Ok(buffer)
     }
 }
@@ -59,8 +119,15 @@ impl<'a> From<&'a str> for Template {
             .split("{%SERIAL%}")
             .map(|part| {
                 part.split("{%IP%}")
-                    .map(|part| TemplateSegment::Static(String::from(part)))
-                    .intersperse(TemplateSegment::Ip)
+                    .map(|part| {
+                        part.split("{%IP6%}")
+                            .map(|part| vec![TemplateSegment::Static(String::from(part))])
+                            .intersperse(vec![TemplateSegment::Ip6])
+                            .flat_map(|s| s)
+                            .collect()
+                    })
+                    .intersperse(vec![TemplateSegment::Ip])
+                    .flat_map(|s: Vec<_>| s)
                     .collect()
             })
             .intersperse(vec![TemplateSegment::Serial])
@@ -99,14 +166,30 @@ mod tests {
         }
     }
 
+    #[derive(Clone, Copy, Debug)]
+    struct Ip6(net::Ipv6Addr);
+
+    impl quickcheck::Arbitrary for Ip6 {
+        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+            let mut segments = [0u16; 8];
+            for s in segments.iter_mut() {
+                *s = g.gen();
+            }
+            Ip6(net::Ipv6Addr::from(segments))
+        }
+    }
+
     impl quickcheck::Arbitrary for TemplateSegment {
         fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
             match g.choose(&[
                 TemplateSegment::Serial,
                 TemplateSegment::Ip,
+                TemplateSegment::Ip6,
                 TemplateSegment::Static("".into()),
             ]) {
-                Some(s @ &TemplateSegment::Ip) | Some(s @ &TemplateSegment::Serial) => s.clone(),
+                Some(s @ &TemplateSegment::Ip)
+                | Some(s @ &TemplateSegment::Ip6)
+                | Some(s @ &TemplateSegment::Serial) => s.clone(),
                 Some(&TemplateSegment::Static(_)) => TemplateSegment::Static(String::arbitrary(g)),
                 None => unreachable!(),
             }
@@ -114,7 +197,7 @@ mod tests {
     }
 
     quickcheck! {
-        fn segments_append_to_text(ip: Ip, now: Time, segments: Vec<TemplateSegment>) -> Result<bool> {
+        fn segments_append_to_text(ip: Ip, ip6: Ip6, now: Time, segments: Vec<TemplateSegment>) -> Result<bool> {
             let mut expected = String::new();
             let mut template = String::new();
 
@@ -131,6 +214,11 @@ mod tests {
                             .chain_err(|| "Error writing ip")?;
                         template += "{%IP%}";
                     },
+                    &TemplateSegment::Ip6 => {
+                        write!(expected, "{}", ip6.0)
+                            .chain_err(|| "Error writing ipv6")?;
+                        template += "{%IP6%}";
+                    },
                     &TemplateSegment::Static(ref s) => {
                         expected += s;
                         template += s;
@@ -140,9 +228,31 @@ mod tests {
 
             let template = Template::from(&template as &str);
 
-            let result = template.render(&ip.0, now.0)?;
+            let addrs = Addresses { v4: Some(ip.0), v6: Some(ip6.0) };
+            let result = template.render(&addrs, now.0)?;
 
             Ok(result == expected)
         }
+
+        fn missing_address_drops_line(ip: Ip, ip6: Ip6, now: Time) -> Result<bool> {
+            let template = Template::from("@ IN A {%IP%}\n@ IN AAAA {%IP6%}\n");
+            let only_v4 = Addresses { v4: Some(ip.0), v6: None };
+            let only_v6 = Addresses { v4: None, v6: Some(ip6.0) };
+
+            Ok(template.render(&only_v4, now.0)? == format!("@ IN A {}\n", ip.0)
+                && template.render(&only_v6, now.0)? == format!("@ IN AAAA {}\n", ip6.0)
+                && template.render(&Addresses::default(), now.0)?.is_empty())
+        }
+    }
+
+    #[test]
+    fn included_families() {
+        let v4: net::IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: net::IpAddr = "2001:db8::1".parse().unwrap();
+
+        let template = Template::from("@ IN A {%IP%}\n");
+        assert!(template.includes(&v4));
+        assert!(!template.includes(&v6));
+        assert!(Template::from("@ IN AAAA {%IP6%}\n").includes(&v6));
     }
 }
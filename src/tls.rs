@@ -0,0 +1,102 @@
+use config::TlsConfig;
+use errors::*;
+use std::sync::{Arc, RwLock};
+use tokio_tls::TlsAcceptor;
+
+/// TLS acceptor whose certificate can be replaced while the server is running.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(config: &TlsConfig) -> Result<Self> {
+        Ok(ReloadableAcceptor {
+            config: config.clone(),
+            acceptor: Arc::new(RwLock::new(load(config)?)),
+        })
+    }
+
+    /// Reads certificate and key again. On failure the previous ones stay in use.
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = load(&self.config)?;
+        *self.acceptor
+            .write()
+            .map_err(|_| "TLS acceptor lock poisoned")? = acceptor;
+        Ok(())
+    }
+
+    /// Returns the acceptor for a new connection.
+    pub fn current(&self) -> Result<TlsAcceptor> {
+        let acceptor = self.acceptor
+            .read()
+            .map_err(|_| "TLS acceptor lock poisoned")?;
+        Ok(acceptor.clone())
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut buffer = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut buffer))
+        .chain_err(|| format!("Error reading {}", path))?;
+    Ok(buffer)
+}
+
+fn load(config: &TlsConfig) -> Result<TlsAcceptor> {
+    use native_tls::{self, Identity};
+
+    let cert = read_file(&config.cert_file)?;
+    let key = read_file(&config.key_file)?;
+
+    let identity = Identity::from_pkcs8(&cert, &key)
+        .chain_err(|| "Error parsing certificate or private key")?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .chain_err(|| "Error creating TLS acceptor")?;
+
+    Ok(acceptor.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn test_config() -> TlsConfig {
+        TlsConfig {
+            cert_file: "res/test/cert.pem".to_owned(),
+            key_file: "res/test/key.pem".to_owned(),
+        }
+    }
+
+    #[test]
+    fn missing_files_are_errors() {
+        let mut config = test_config();
+        config.key_file = "res/test/missing.pem".to_owned();
+
+        assert!(ReloadableAcceptor::new(&config).is_err());
+    }
+
+    #[test]
+    fn failed_reload_keeps_acceptor() {
+        let cert_file = env::temp_dir().join(format!("dyndns-daemon-cert-{}", ::std::process::id()));
+        fs::copy("res/test/cert.pem", &cert_file).unwrap();
+
+        let mut config = test_config();
+        config.cert_file = cert_file.to_string_lossy().into_owned();
+
+        let acceptor = ReloadableAcceptor::new(&config).unwrap();
+        acceptor.reload().unwrap();
+
+        fs::write(&cert_file, "garbage").unwrap();
+        assert!(acceptor.reload().is_err());
+        assert!(acceptor.current().is_ok());
+
+        fs::remove_file(&cert_file).unwrap();
+    }
+}